@@ -0,0 +1,207 @@
+//! Adds `csvify` to turn its arguments into a single RFC 4180 CSV line, and
+//! `csvify_header` to create the matching header line from argument names.
+//!
+//! Add with
+//!
+//! ```sql
+//! CREATE FUNCTION csvify RETURNS string SONAME 'libudf_csvify.so';
+//! CREATE FUNCTION csvify_header RETURNS string SONAME 'libudf_csvify.so';
+//! ```
+//!
+//! Fields are quoted only if they contain the delimiter, the quote character
+//! or a line break, and quote characters within fields are doubled. `NULL` is
+//! an empty field and an empty string is an empty quoted field (`""`), so the
+//! two can be told apart. Lines do not include a line terminator.
+//!
+//! Both functions accept a leading options argument to change the delimiter
+//! and quoting, e.g. `csvify('delimiter=tab' AS "$opts", a, b)`; see
+//! `options.rs` for everything that is available.
+
+mod options;
+
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+use crate::options::Options;
+
+/// Append a single field to `out`, quoting it if needed
+fn push_field(out: &mut String, field: &str, opts: &Options) {
+    let needs_quotes = opts.quote_all
+        || field.is_empty()
+        || field.contains([opts.delimiter, opts.quote, '\r', '\n']);
+
+    if !needs_quotes {
+        out.push_str(field);
+        return;
+    }
+
+    out.push(opts.quote);
+    for ch in field.chars() {
+        if ch == opts.quote {
+            out.push(ch);
+        }
+        out.push(ch);
+    }
+    out.push(opts.quote);
+}
+
+/// Create a CSV line from all arguments
+#[derive(Debug, Default)]
+struct Csvify {
+    opts: Options,
+    /// Number of leading arguments used for options
+    skip: usize,
+    res: String,
+}
+
+#[register]
+impl BasicUdf for Csvify {
+    type Returns<'a> = &'a str;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let (opts, skip) = Options::from_args(args)?;
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        Ok(Self {
+            opts,
+            skip,
+            res: String::new(),
+        })
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        self.res.clear();
+
+        for (i, arg) in args.iter().skip(self.skip).enumerate() {
+            if i > 0 {
+                self.res.push(self.opts.delimiter);
+            }
+
+            let field = match arg.value() {
+                SqlResult::String(Some(v)) => String::from_utf8_lossy(v).into_owned(),
+                SqlResult::Real(Some(v)) => v.to_string(),
+                SqlResult::Int(Some(v)) => v.to_string(),
+                SqlResult::Decimal(Some(v)) => v.to_owned(),
+                // `NULL` is the only unquoted empty field
+                _ => continue,
+            };
+            push_field(&mut self.res, &field, &self.opts);
+        }
+
+        Ok(&self.res)
+    }
+}
+
+/// Create a CSV line from the names of all arguments
+///
+/// The result never changes, so it is built during `init`.
+#[derive(Debug, Default)]
+struct CsvifyHeader(String);
+
+#[register]
+impl BasicUdf for CsvifyHeader {
+    type Returns<'a> = &'a str;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let (opts, skip) = Options::from_args(args)?;
+        let mut res = String::new();
+
+        for (i, arg) in args.iter().skip(skip).enumerate() {
+            if i > 0 {
+                res.push(opts.delimiter);
+            }
+            push_field(&mut res, arg.attribute(), &opts);
+        }
+
+        cfg.set_max_len(res.len() as u64);
+        cfg.set_is_const(true);
+        Ok(Self(res))
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        _args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        Ok(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_csvify() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            (10, "qty", false),
+            (50.25, "cost", false),
+            (Decimal "10.50", "price", false),
+            ("plain", "a", false),
+            ("has, comma", "b", false),
+            ("say \"hi\"", "c", false),
+            ("two\nlines", "d", false),
+            ("", "e", false),
+            (String None, "f", true),
+            (Int None, "g", true),
+            (&b"\xffx"[..], "h", false),
+        ];
+
+        let mut csvify = Csvify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = csvify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(
+            res,
+            Ok("10,50.25,10.50,plain,\"has, comma\",\"say \"\"hi\"\"\",\"two\nlines\",\"\",,,\u{fffd}x")
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            ("delimiter=tab quote=single", "$opts", false),
+            ("a,b", "x", false),
+            ("it's\tx", "y", false),
+            (String None, "z", true),
+        ];
+
+        let mut csvify = Csvify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = csvify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok("a,b\t'it''s\tx'\t"));
+
+        let mut arglist = mock_args![
+            ("quote_all", "$opts", false),
+            (1, "x", false),
+            ("a", "y", false)
+        ];
+        let mut csvify = Csvify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = csvify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(r#""1","a""#));
+
+        let mut arglist = mock_args![("delimiter=nope", "$opts", false), (1, "x", false)];
+        assert!(Csvify::init(cfg.as_init(), arglist.as_init()).is_err());
+    }
+
+    #[test]
+    fn test_header() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            ("delimiter=semicolon", "$opts", false),
+            (1, "id", false),
+            ("x", "full name", false),
+            ("y", "a;b", false),
+        ];
+
+        let mut header = CsvifyHeader::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = header.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok("id;full name;\"a;b\""));
+    }
+}
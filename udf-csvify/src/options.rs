@@ -0,0 +1,171 @@
+//! Options for `csvify` and `csvify_header`
+//!
+//! Options are passed as a constant string in a leading argument that is
+//! aliased as `$opts`, the same as for `jsonify`. Entries are separated by
+//! commas or whitespace and are either flags or `key=value` pairs:
+//!
+//! ```sql
+//! SELECT csvify('delimiter=semicolon quote_all' AS "$opts", a, b) FROM t1;
+//! ```
+//!
+//! Available options:
+//!
+//! - `delimiter=X`: the field separator. `comma` (the default), `semicolon`,
+//!   `tab`, `pipe` and `space` name common choices, and any other single
+//!   character can be given directly.
+//! - `quote=X`: the quote character. `double` (`"`, the default) or `single`
+//!   (`'`), or any other single character.
+//! - `quote_all`: quote every non-`NULL` field, rather than only those that
+//!   need it
+
+use udf::prelude::*;
+
+/// Alias that marks an argument as the options string
+pub const OPTIONS_ALIAS: &str = "$opts";
+
+/// Parsed options
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    pub delimiter: char,
+    pub quote: char,
+    pub quote_all: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            quote: '"',
+            quote_all: false,
+        }
+    }
+}
+
+/// Parse a single character option value, allowing some names
+fn parse_char(key: &str, v: &str, names: &[(&str, char)]) -> Result<char, String> {
+    if let Some((_, ch)) = names.iter().find(|(name, _)| *name == v) {
+        return Ok(*ch);
+    }
+
+    let mut chars = v.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) if !matches!(ch, '\r' | '\n') => Ok(ch),
+        _ => {
+            let names: Vec<_> = names.iter().map(|(name, _)| format!("'{name}'")).collect();
+            Err(format!(
+                "invalid value '{v}' for option '{key}'; expected {} or a single character",
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+impl Options {
+    /// Read options from the first argument if it is aliased as `$opts`
+    ///
+    /// Returns the options and the number of arguments they consumed (0 or 1),
+    /// so callers know where their data arguments start.
+    pub fn from_args(args: &ArgList<Init>) -> Result<(Self, usize), String> {
+        let mut ret = Self::default();
+        let Some(first) = args.get(0) else {
+            return Ok((ret, 0));
+        };
+
+        if first.attribute() != OPTIONS_ALIAS {
+            return Ok((ret, 0));
+        }
+
+        let value = first.value();
+        let Some(opt_str) = value.as_string() else {
+            return Err(format!(
+                "argument `{OPTIONS_ALIAS}` must be a constant string"
+            ));
+        };
+
+        ret.update(opt_str)?;
+        Ok((ret, 1))
+    }
+
+    /// Parse an options string
+    #[cfg(test)]
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut ret = Self::default();
+        ret.update(s)?;
+        Ok(ret)
+    }
+
+    /// Apply an options string on top of the current options
+    fn update(&mut self, s: &str) -> Result<(), String> {
+        for item in s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|item| !item.is_empty())
+        {
+            let (key, value) = match item.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (item, None),
+            };
+
+            match (key, value) {
+                ("quote_all", None) => self.quote_all = true,
+                ("delimiter", Some(v)) => {
+                    self.delimiter = parse_char(
+                        key,
+                        v,
+                        &[
+                            ("comma", ','),
+                            ("semicolon", ';'),
+                            ("tab", '\t'),
+                            ("pipe", '|'),
+                            ("space", ' '),
+                        ],
+                    )?;
+                }
+                ("quote", Some(v)) => {
+                    self.quote = parse_char(key, v, &[("double", '"'), ("single", '\'')])?;
+                }
+                _ => return Err(format!("unrecognized option '{item}'")),
+            }
+        }
+
+        if self.delimiter == self.quote {
+            return Err("options 'delimiter' and 'quote' must be different".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Options::parse(""), Ok(Options::default()));
+        assert_eq!(
+            Options::parse("delimiter=tab, quote=single quote_all"),
+            Ok(Options {
+                delimiter: '\t',
+                quote: '\'',
+                quote_all: true,
+            })
+        );
+        assert_eq!(
+            Options::parse("delimiter=; quote=`"),
+            Ok(Options {
+                delimiter: ';',
+                quote: '`',
+                quote_all: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Options::parse("delimiter=").is_err());
+        assert!(Options::parse("delimiter=ab").is_err());
+        assert!(Options::parse("quote_all=yes").is_err());
+        assert!(Options::parse("quote=single delimiter='").is_err());
+        assert!(Options::parse("unknown").is_err());
+    }
+}
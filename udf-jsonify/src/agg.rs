@@ -0,0 +1,519 @@
+//! Aggregate functions that collect rows into JSON
+
+use serde_json::{Map, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+use crate::options::Options;
+use crate::output::to_json_string;
+use crate::{res_to_json_val, ObjectSpec};
+
+/// Largest result we will build for a single group
+const MAX_LEN: usize = MaxLenOptions::Blob as usize;
+
+/// Largest result for `jsonify_lines`, which is meant for bulk exports
+const LINES_MAX_LEN: usize = MaxLenOptions::MediumBlob as usize;
+
+/// Aggregate `jsonify`, creates an array with one object per row
+#[derive(Debug, Default)]
+struct JsonifyAgg {
+    spec: ObjectSpec,
+    /// Result array, missing its closing `]` until `process` is called
+    res: String,
+}
+
+#[register]
+impl BasicUdf for JsonifyAgg {
+    type Returns<'a> = &'a str;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        cfg.set_max_len(MAX_LEN as u64);
+        Ok(Self {
+            spec: ObjectSpec::from_args(args, Options::default())?,
+            res: String::new(),
+        })
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        _args: &ArgList<Process>,
+        error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        if error.is_some() {
+            return Err(ProcessError);
+        }
+        self.res.push(']');
+        Ok(&self.res)
+    }
+}
+
+#[register]
+impl AggregateUdf for JsonifyAgg {
+    fn clear(
+        &mut self,
+        _cfg: &UdfCfg<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<(), NonZeroU8> {
+        self.res.clear();
+        self.res.push('[');
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        error: Option<NonZeroU8>,
+    ) -> Result<(), NonZeroU8> {
+        // Nothing to do if we have already failed for this group
+        error.map_or(Ok(()), Err)?;
+
+        let err_val = NonZeroU8::new(1).unwrap();
+        let obj = self.spec.build(args).map_err(|_| err_val)?;
+
+        if self.res.len() > 1 {
+            self.res.push(',');
+        }
+        self.res.push_str(&to_json_string(&obj, &self.spec.opts));
+
+        // Leave room for the closing bracket
+        if self.res.len() + 1 > MAX_LEN {
+            udf_log!(Warning: "jsonify_agg: result exceeds the maximum length of {MAX_LEN} bytes; returning NULL");
+            return Err(err_val);
+        }
+
+        Ok(())
+    }
+}
+
+/// Aggregate `jsonify` that creates JSON Lines, with one object per row
+///
+/// Every line, including the last, ends with `\n` so that results can be
+/// concatenated.
+#[derive(Debug, Default)]
+struct JsonifyLines {
+    spec: ObjectSpec,
+    res: String,
+}
+
+#[register]
+impl BasicUdf for JsonifyLines {
+    type Returns<'a> = &'a str;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let spec = ObjectSpec::from_args(args, Options::default())?;
+        if spec.opts.indent.is_some() {
+            return Err(
+                "jsonify_lines: option 'indent' is not allowed, since every object \
+                must be on a single line"
+                    .to_owned(),
+            );
+        }
+        cfg.set_max_len(LINES_MAX_LEN as u64);
+        Ok(Self {
+            spec,
+            res: String::new(),
+        })
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        _args: &ArgList<Process>,
+        error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        if error.is_some() {
+            return Err(ProcessError);
+        }
+        Ok(&self.res)
+    }
+}
+
+#[register]
+impl AggregateUdf for JsonifyLines {
+    fn clear(
+        &mut self,
+        _cfg: &UdfCfg<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<(), NonZeroU8> {
+        self.res.clear();
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        error: Option<NonZeroU8>,
+    ) -> Result<(), NonZeroU8> {
+        error.map_or(Ok(()), Err)?;
+
+        let err_val = NonZeroU8::new(1).unwrap();
+        let obj = self.spec.build(args).map_err(|_| err_val)?;
+
+        self.res.push_str(&to_json_string(&obj, &self.spec.opts));
+        self.res.push('\n');
+
+        if self.res.len() > LINES_MAX_LEN {
+            udf_log!(Warning: "jsonify_lines: result exceeds the maximum length of {LINES_MAX_LEN} bytes; returning NULL");
+            return Err(err_val);
+        }
+
+        Ok(())
+    }
+}
+
+/// What `json_object_agg` does when it sees a key more than once in a group
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum DupKeys {
+    /// Keep the most recent value
+    #[default]
+    Last,
+    /// Keep the first value
+    First,
+    /// Collect all values for the key into an array, even if there is only one
+    Collect,
+    /// Return `NULL` for the group
+    Error,
+}
+
+/// Aggregate key/value pairs into a single object
+#[derive(Debug, Default)]
+struct JsonObjectAgg {
+    dup_keys: DupKeys,
+    /// With `DupKeys::Collect`, every value is an array of everything seen
+    map: Map<String, Value>,
+    /// Upper bound of the serialized length of `map`
+    size: usize,
+    res: String,
+}
+
+/// Length of `value` once serialized as JSON
+fn json_len(value: &Value) -> usize {
+    value.to_string().len()
+}
+
+#[register]
+impl BasicUdf for JsonObjectAgg {
+    type Returns<'a> = &'a str;
+
+    /// Expect `json_object_agg(key, value)` or `json_object_agg(key, value, policy)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(format!(
+                "json_object_agg takes 2 or 3 arguments but got {} (usage: \
+                `json_object_agg(key, value)` or `json_object_agg(key, value, policy)`)",
+                args.len()
+            ));
+        }
+
+        args.get(0).unwrap().set_type_coercion(SqlType::String);
+
+        let dup_keys = match args.get(2) {
+            None => DupKeys::default(),
+            Some(arg) => match arg.value().as_string() {
+                Some("last") => DupKeys::Last,
+                Some("first") => DupKeys::First,
+                Some("collect") => DupKeys::Collect,
+                Some("error") => DupKeys::Error,
+                _ => {
+                    return Err(
+                        "json_object_agg policy must be a constant 'last', 'first', 'collect' \
+                        or 'error'"
+                            .to_owned(),
+                    )
+                }
+            },
+        };
+
+        cfg.set_max_len(MAX_LEN as u64);
+        Ok(Self {
+            dup_keys,
+            ..Default::default()
+        })
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        _args: &ArgList<Process>,
+        error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        if error.is_some() {
+            return Err(ProcessError);
+        }
+
+        self.res = Value::Object(std::mem::take(&mut self.map)).to_string();
+        if self.res.len() > MAX_LEN {
+            udf_log!(Warning: "json_object_agg: result exceeds the maximum length of {MAX_LEN} bytes; returning NULL");
+            return Err(ProcessError);
+        }
+
+        Ok(&self.res)
+    }
+}
+
+#[register]
+impl AggregateUdf for JsonObjectAgg {
+    fn clear(
+        &mut self,
+        _cfg: &UdfCfg<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<(), NonZeroU8> {
+        self.map.clear();
+        self.size = 2;
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        error: Option<NonZeroU8>,
+    ) -> Result<(), NonZeroU8> {
+        error.map_or(Ok(()), Err)?;
+
+        // Rows with a null key are skipped
+        let key_arg = args.get(0).unwrap().value();
+        let Some(key) = key_arg.as_bytes().map(String::from_utf8_lossy) else {
+            return Ok(());
+        };
+        let value = res_to_json_val(args.get(1).unwrap().value(), &Options::default())
+            .map_err(|_| NonZeroU8::new(1).unwrap())?;
+
+        let value_len = json_len(&value);
+        match (self.map.get_mut(key.as_ref()), self.dup_keys) {
+            (None, dup_keys) => {
+                // Key, colon and comma, plus brackets when collecting
+                let key_len = json_len(&Value::String(key.as_ref().to_owned()));
+                self.size += key_len + value_len + 2;
+                if dup_keys == DupKeys::Collect {
+                    self.size += 2;
+                    self.map.insert(key.into_owned(), Value::Array(vec![value]));
+                } else {
+                    self.map.insert(key.into_owned(), value);
+                }
+            }
+            (Some(existing), DupKeys::Last) => {
+                self.size = self.size - json_len(existing) + value_len;
+                *existing = value;
+            }
+            (Some(_), DupKeys::First) => (),
+            (Some(Value::Array(arr)), DupKeys::Collect) => {
+                self.size += value_len + 1;
+                arr.push(value);
+            }
+            (Some(_), DupKeys::Collect) => unreachable!("collected values are always arrays"),
+            (Some(_), DupKeys::Error) => {
+                udf_log!(Warning: "json_object_agg: duplicate key '{key}'; returning NULL");
+                return Err(NonZeroU8::new(1).unwrap());
+            }
+        }
+
+        if self.size > MAX_LEN {
+            udf_log!(Warning: "json_object_agg: result exceeds the maximum length of {MAX_LEN} bytes; returning NULL");
+            return Err(NonZeroU8::new(1).unwrap());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_agg() {
+        let mut cfg = MockUdfCfg::new();
+        let mut rows = [
+            mock_args![(1, "id", false), ("foo", "name", false)],
+            mock_args![(2, "id", false), ("bar", "name", false)],
+        ];
+
+        let mut agg = JsonifyAgg::init(cfg.as_init(), rows[0].as_init()).unwrap();
+
+        // Run twice to make sure that `clear` resets everything
+        for _ in 0..2 {
+            agg.clear(cfg.as_process(), None).unwrap();
+            for row in rows.iter_mut() {
+                agg.add(cfg.as_process(), row.as_process(), None).unwrap();
+            }
+            let res = agg.process(cfg.as_process(), rows[0].as_process(), None);
+            assert_eq!(res, Ok(r#"[{"id":1,"name":"foo"},{"id":2,"name":"bar"}]"#));
+        }
+
+        // Empty group
+        agg.clear(cfg.as_process(), None).unwrap();
+        let res = agg.process(cfg.as_process(), rows[0].as_process(), None);
+        assert_eq!(res, Ok("[]"));
+    }
+
+    #[test]
+    fn test_agg_too_long() {
+        let mut cfg = MockUdfCfg::new();
+        let long_str = "x".repeat(MAX_LEN / 4);
+        let mut row = mock_args![(String long_str.as_str(), "s", false)];
+
+        let mut agg = JsonifyAgg::init(cfg.as_init(), row.as_init()).unwrap();
+        agg.clear(cfg.as_process(), None).unwrap();
+
+        let mut error = None;
+        for _ in 0..4 {
+            if let Err(e) = agg.add(cfg.as_process(), row.as_process(), error) {
+                error = Some(e);
+            }
+        }
+
+        assert!(error.is_some());
+        let res = agg.process(cfg.as_process(), row.as_process(), error);
+        assert_eq!(res, Err(ProcessError));
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut cfg = MockUdfCfg::new();
+        let mut rows = [
+            mock_args![(1, "id", false), ("a\nb", "user.name", false)],
+            mock_args![(2, "id", false), (String None, "user.name", true)],
+        ];
+
+        let mut agg = JsonifyLines::init(cfg.as_init(), rows[0].as_init()).unwrap();
+
+        for _ in 0..2 {
+            agg.clear(cfg.as_process(), None).unwrap();
+            for row in rows.iter_mut() {
+                agg.add(cfg.as_process(), row.as_process(), None).unwrap();
+            }
+            let res = agg.process(cfg.as_process(), rows[0].as_process(), None);
+            assert_eq!(
+                res,
+                Ok(concat!(
+                    r#"{"id":1,"user":{"name":"a\nb"}}"#,
+                    "\n",
+                    r#"{"id":2,"user":{"name":null}}"#,
+                    "\n"
+                ))
+            );
+        }
+
+        agg.clear(cfg.as_process(), None).unwrap();
+        let res = agg.process(cfg.as_process(), rows[0].as_process(), None);
+        assert_eq!(res, Ok(""));
+
+        let mut args = mock_args![("indent=2", "$opts", false), (1, "id", false)];
+        assert!(JsonifyLines::init(cfg.as_init(), args.as_init()).is_err());
+    }
+
+    #[test]
+    fn test_lines_too_long() {
+        let mut cfg = MockUdfCfg::new();
+        let long_str = "x".repeat(LINES_MAX_LEN / 4);
+        let mut row = mock_args![(String long_str.as_str(), "s", false)];
+
+        let mut agg = JsonifyLines::init(cfg.as_init(), row.as_init()).unwrap();
+        agg.clear(cfg.as_process(), None).unwrap();
+
+        let mut error = None;
+        for _ in 0..4 {
+            if let Err(e) = agg.add(cfg.as_process(), row.as_process(), error) {
+                error = Some(e);
+            }
+        }
+
+        assert!(error.is_some());
+        let res = agg.process(cfg.as_process(), row.as_process(), error);
+        assert_eq!(res, Err(ProcessError));
+    }
+
+    /// Run a single group through `json_object_agg` with the given policy
+    fn run_object_agg(policy: Option<&str>) -> Result<String, ProcessError> {
+        let mut cfg = MockUdfCfg::new();
+        let pairs = [("a", 1), ("b", 2), ("a", 3)];
+        let mut rows: Vec<_> = pairs
+            .iter()
+            .map(|(k, v)| match policy {
+                Some(p) => mock_args![(*k, "k", false), (*v, "v", false), (p, "p", false)],
+                None => mock_args![(*k, "k", false), (*v, "v", false)],
+            })
+            .collect();
+
+        let mut agg = JsonObjectAgg::init(cfg.as_init(), rows[0].as_init()).unwrap();
+        agg.clear(cfg.as_process(), None).unwrap();
+
+        let mut error = None;
+        for row in rows.iter_mut() {
+            if let Err(e) = agg.add(cfg.as_process(), row.as_process(), error) {
+                error = Some(e);
+            }
+        }
+
+        agg.process(cfg.as_process(), rows[0].as_process(), error)
+            .map(ToOwned::to_owned)
+    }
+
+    #[test]
+    fn test_object_agg() {
+        assert_eq!(run_object_agg(None).unwrap(), r#"{"a":3,"b":2}"#);
+        assert_eq!(run_object_agg(Some("last")).unwrap(), r#"{"a":3,"b":2}"#);
+        assert_eq!(run_object_agg(Some("first")).unwrap(), r#"{"a":1,"b":2}"#);
+        assert_eq!(
+            run_object_agg(Some("collect")).unwrap(),
+            r#"{"a":[1,3],"b":[2]}"#
+        );
+        assert_eq!(run_object_agg(Some("error")), Err(ProcessError));
+    }
+
+    #[test]
+    fn test_object_agg_max_len() {
+        let mut cfg = MockUdfCfg::new();
+        let value = "x".repeat(4000);
+        let mut rows: Vec<_> = (0..20)
+            .map(|i| {
+                mock_args![
+                    (format!("k{}", i % 2).as_str(), "k", false),
+                    (value.as_str(), "v", false)
+                ]
+            })
+            .collect();
+
+        // Replacing values doesn't grow the result
+        let mut agg = JsonObjectAgg::init(cfg.as_init(), rows[0].as_init()).unwrap();
+        agg.clear(cfg.as_process(), None).unwrap();
+        for row in rows.iter_mut() {
+            agg.add(cfg.as_process(), row.as_process(), None).unwrap();
+        }
+        let res = agg.process(cfg.as_process(), rows[0].as_process(), None);
+        assert_eq!(res.unwrap().len(), 2 * (value.len() + 7) + 3);
+
+        // Collecting fails as soon as the result is too long
+        let mut rows: Vec<_> = (0..20)
+            .map(|_| {
+                mock_args![
+                    ("k", "k", false),
+                    (value.as_str(), "v", false),
+                    ("collect", "p", false)
+                ]
+            })
+            .collect();
+        let mut agg = JsonObjectAgg::init(cfg.as_init(), rows[0].as_init()).unwrap();
+        agg.clear(cfg.as_process(), None).unwrap();
+        let failed = rows
+            .iter_mut()
+            .position(|row| agg.add(cfg.as_process(), row.as_process(), None).is_err());
+        assert_eq!(failed, Some(16));
+    }
+
+    #[test]
+    fn test_object_agg_init() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![("k", "k", false)];
+        assert!(JsonObjectAgg::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![("k", "k", false), (1, "v", false), ("bad", "p", false)];
+        assert!(JsonObjectAgg::init(cfg.as_init(), args.as_init()).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Canonical JSON as described in [RFC 8785] (JSON Canonicalization Scheme)
+//!
+//! Output is byte-for-byte stable so it can be hashed or signed: object keys
+//! are sorted by their UTF-16 code units, there is no whitespace, strings use
+//! minimal escaping, and numbers are formatted the way ECMAScript does it.
+//! Numbers are first converted to IEEE 754 doubles as the RFC requires, so
+//! very large or very precise numbers will lose precision.
+//!
+//! [RFC 8785]: https://www.rfc-editor.org/rfc/rfc8785
+
+use serde_json::{Number, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+use crate::{parse_json_arg, Jsonify};
+
+/// `jsonify` with canonical output
+#[derive(Debug, Default)]
+struct JsonifyCanonical(Jsonify);
+
+#[register]
+impl BasicUdf for JsonifyCanonical {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Accept the same options as `jsonify`, except for those that change
+    /// formatting or key order, which RFC 8785 fixes
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let jsonify = Jsonify::init(cfg, args)?;
+        let opts = &jsonify.spec.opts;
+        let ignored = [
+            ("indent", opts.indent.is_some()),
+            ("ascii", opts.ascii),
+            ("sorted", opts.sorted),
+        ];
+        if let Some((name, _)) = ignored.iter().find(|(_, set)| *set) {
+            return Err(format!(
+                "jsonify_canonical does not accept option '{name}'; canonical output always \
+                has sorted keys, no whitespace and minimal escaping"
+            ));
+        }
+        Ok(Self(jsonify))
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let Ok(json) = self.0.spec.build(args) else {
+            return Ok(None);
+        };
+        match to_canonical_string(&json) {
+            Ok(res) => self.0.res = res,
+            Err(e) => {
+                udf_log!(Warning: "jsonify_canonical: {e}");
+                return Ok(None);
+            }
+        }
+        Ok(Some(&self.0.res))
+    }
+}
+
+/// Canonicalize an existing JSON document
+#[derive(Debug, Default)]
+struct JsonCanonicalize(String);
+
+#[register]
+impl BasicUdf for JsonCanonicalize {
+    type Returns<'a> = Option<&'a str>;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        if args.len() != 1 {
+            return Err(format!(
+                "json_canonicalize takes 1 argument but got {}",
+                args.len()
+            ));
+        }
+        args.get(0).unwrap().set_type_coercion(SqlType::String);
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        cfg.set_maybe_null(true);
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let Some(json) = parse_json_arg(args.get(0).unwrap().value(), "json_canonicalize") else {
+            return Ok(None);
+        };
+        match to_canonical_string(&json) {
+            Ok(res) => self.0 = res,
+            Err(e) => {
+                udf_log!(Warning: "json_canonicalize: {e}");
+                return Ok(None);
+            }
+        }
+        Ok(Some(&self.0))
+    }
+}
+
+/// Serialize a value according to RFC 8785
+pub fn to_canonical_string(value: &Value) -> Result<String, String> {
+    let mut ret = String::new();
+    write_canonical(value, &mut ret)?;
+    Ok(ret)
+}
+
+fn write_canonical(value: &Value, out: &mut String) -> Result<(), String> {
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) => out.push_str(&value.to_string()),
+        Value::Number(num) => out.push_str(&format_number(num)?),
+        Value::Array(arr) => {
+            out.push('[');
+            for (i, item) in arr.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by(|a, b| a.0.encode_utf16().cmp(b.0.encode_utf16()));
+
+            out.push('{');
+            for (i, (key, val)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                // serde_json's string escaping is the same as the RFC's
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(val, out)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+/// Format a number the same as ECMAScript's `Number.prototype.toString`
+fn format_number(num: &Number) -> Result<String, String> {
+    let val = num
+        .as_f64()
+        .filter(|v| v.is_finite())
+        .ok_or_else(|| format!("number {num} cannot be represented as a double"))?;
+
+    if val == 0.0 {
+        // Includes negative zero
+        return Ok("0".to_owned());
+    }
+
+    // Rust gives us the shortest round trip representation, we just need to
+    // rearrange it
+    let sci = format!("{:e}", val.abs());
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    let n = exp.parse::<i32>().unwrap() + 1;
+
+    let mut ret = String::new();
+    if val < 0.0 {
+        ret.push('-');
+    }
+
+    if k <= n && n <= 21 {
+        ret.push_str(&digits);
+        ret.extend(std::iter::repeat_n('0', (n - k) as usize));
+    } else if 0 < n && n <= 21 {
+        ret.push_str(&digits[..n as usize]);
+        ret.push('.');
+        ret.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        ret.push_str("0.");
+        ret.extend(std::iter::repeat_n('0', (-n) as usize));
+        ret.push_str(&digits);
+    } else {
+        ret.push_str(&digits[..1]);
+        if k > 1 {
+            ret.push('.');
+            ret.push_str(&digits[1..]);
+        }
+        ret.push('e');
+        ret.push(if n - 1 < 0 { '-' } else { '+' });
+        ret.push_str(&(n - 1).abs().to_string());
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use udf::mock::*;
+
+    use super::*;
+
+    fn fmt(v: &str) -> String {
+        format_number(&v.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_numbers() {
+        // Examples from RFC 8785 appendix B
+        assert_eq!(fmt("0"), "0");
+        assert_eq!(fmt("-0"), "0");
+        assert_eq!(fmt("5e-324"), "5e-324");
+        assert_eq!(fmt("-5e-324"), "-5e-324");
+        assert_eq!(fmt("1.7976931348623157e308"), "1.7976931348623157e+308");
+        assert_eq!(fmt("9007199254740992"), "9007199254740992");
+        assert_eq!(fmt("9007199254740993"), "9007199254740992");
+        assert_eq!(fmt("295147905179352830000"), "295147905179352830000");
+        assert_eq!(fmt("1e21"), "1e+21");
+        assert_eq!(fmt("1e20"), "100000000000000000000");
+        assert_eq!(fmt("0.000001"), "0.000001");
+        assert_eq!(fmt("0.0000001"), "1e-7");
+        assert_eq!(fmt("333333333.3333333"), "333333333.3333333");
+        assert_eq!(fmt("1.50"), "1.5");
+        assert_eq!(fmt("-1.25e-10"), "-1.25e-10");
+        assert!(format_number(&"1e400".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_canonical() {
+        // Example from RFC 8785 section 3.2.2
+        let input = r#"{
+            "numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001],
+            "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/",
+            "literals": [null, true, false]
+        }"#;
+        let expected = concat!(
+            r#"{"literals":[null,true,false],"numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27],"#,
+            r#""string":"€$\u000f\nA'B\"\\\\\"/"}"#
+        );
+        let val: Value = serde_json::from_str(input).unwrap();
+        assert_eq!(to_canonical_string(&val).unwrap(), expected);
+
+        // Sorting by UTF-16 code units puts U+1F600 (surrogates) before U+FB33
+        let val = json!({"\u{fb33}": 1, "\u{1f600}": 2, "a": 3, "\r": 4});
+        assert_eq!(
+            to_canonical_string(&val).unwrap(),
+            "{\"\\r\":4,\"a\":3,\"\u{1f600}\":2,\"\u{fb33}\":1}"
+        );
+    }
+
+    #[test]
+    fn test_init_errors() {
+        let mut cfg = MockUdfCfg::new();
+        for opts in ["indent=2", "ascii", "sorted", "nulls=omit sorted"] {
+            let mut arglist = mock_args![(opts, "$opts", false), (1, "a", false)];
+            let err = JsonifyCanonical::init(cfg.as_init(), arglist.as_init()).unwrap_err();
+            assert!(err.contains("does not accept"), "{opts}: {err}");
+        }
+
+        let mut arglist = mock_args![("nulls=omit", "$opts", false), (Int None, "a", true)];
+        let mut jsonify = JsonifyCanonical::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some("{}")));
+    }
+
+    #[test]
+    fn test_udfs() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            ("x", "b", false),
+            (Decimal "1.50", "a", false),
+            ("é", "c", false)
+        ];
+
+        let mut jsonify = JsonifyCanonical::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"a":1.5,"b":"x","c":"é"}"#)));
+
+        let mut arglist = mock_args![(r#"{"b": [1.0, 2], "a": {}}"#, "doc", false)];
+        let mut canon = JsonCanonicalize::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = canon.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"a":{},"b":[1,2]}"#)));
+
+        let mut arglist = mock_args![(String None, "doc", true)];
+        let res = canon.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        // Invalid input only affects its own row
+        for doc in ["{not json", "[1e400]"] {
+            let mut arglist = mock_args![(doc, "doc", false)];
+            let res = canon.process(cfg.as_process(), arglist.as_process(), None);
+            assert_eq!(res, Ok(None));
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! Converting configuration formats to and from JSON
+//!
+//! `yaml_to_json` and `toml_to_json` parse YAML and TOML text, and
+//! `json_to_yaml` writes a JSON document as YAML. Keys keep their order.
+//!
+//! Input that can't be parsed returns `NULL` with a warning, rather than
+//! failing the query, so that one bad row doesn't hide the rest.
+//!
+//! YAML keys that are not strings are converted to their JSON text, and aliases
+//! are replaced with a copy of their anchor. Since nested aliases can make a
+//! small input expand enormously, documents that would expand to more than
+//! `MAX_YAML_NODES` nodes are rejected before they are loaded. Input with more
+//! than one YAML document is an error, and empty input is `null`. YAML floats
+//! keep their original text when it is a valid JSON number. Non-finite floats
+//! from either format become `null`, and TOML datetimes become strings.
+
+use std::collections::HashMap;
+
+use serde_json::{Map, Number, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+use yaml_rust2::parser::Parser;
+use yaml_rust2::yaml::Hash;
+use yaml_rust2::{Event, Yaml, YamlEmitter, YamlLoader};
+
+use crate::packed::key_string;
+
+/// Most nodes a YAML document may expand to, counting every alias as a full
+/// copy of its anchor. Each node needs at least a byte of output, so anything
+/// larger could not be returned anyway.
+const MAX_YAML_NODES: usize = MaxLenOptions::Blob as usize;
+
+/// Check for a single text argument
+fn init_text(cfg: &UdfCfg<Init>, args: &ArgList<Init>, name: &str) -> Result<(), String> {
+    if args.len() != 1 {
+        return Err(format!(
+            "{name} takes 1 argument but got {} (usage: `{name}(text)`)",
+            args.len()
+        ));
+    }
+    args.get(0).unwrap().set_type_coercion(SqlType::String);
+    cfg.set_max_len(MaxLenOptions::Blob as u64);
+    cfg.set_maybe_null(true);
+    Ok(())
+}
+
+/// Run `convert` on the argument, storing the result in `res`
+///
+/// Returns `None` if the argument is `NULL` or can't be converted.
+fn convert_text<'a>(
+    res: &'a mut String,
+    args: &ArgList<Process>,
+    name: &str,
+    convert: fn(&str) -> Result<String, String>,
+) -> Option<&'a str> {
+    let arg = args.get(0).unwrap().value();
+    let bytes = arg.as_bytes()?;
+    let converted = std::str::from_utf8(bytes)
+        .map_err(|e| format!("argument is not valid UTF-8: {e}"))
+        .and_then(convert);
+    match converted {
+        Ok(s) => {
+            *res = s;
+            Some(res)
+        }
+        Err(e) => {
+            udf_log!(Warning: "{name}: {e}");
+            None
+        }
+    }
+}
+
+/// Convert YAML to JSON
+#[derive(Debug, Default)]
+struct YamlToJson(String);
+
+#[register]
+impl BasicUdf for YamlToJson {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `yaml_to_json(text)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_text(cfg, args, "yaml_to_json")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        Ok(convert_text(&mut self.0, args, "yaml_to_json", |s| {
+            parse_yaml(s).map(|v| v.to_string())
+        }))
+    }
+}
+
+/// Convert TOML to JSON
+#[derive(Debug, Default)]
+struct TomlToJson(String);
+
+#[register]
+impl BasicUdf for TomlToJson {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `toml_to_json(text)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_text(cfg, args, "toml_to_json")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        Ok(convert_text(&mut self.0, args, "toml_to_json", |s| {
+            parse_toml(s).map(|v| v.to_string())
+        }))
+    }
+}
+
+/// Convert JSON to YAML
+#[derive(Debug, Default)]
+struct JsonToYaml(String);
+
+#[register]
+impl BasicUdf for JsonToYaml {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_to_yaml(doc)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_text(cfg, args, "json_to_yaml")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        Ok(convert_text(&mut self.0, args, "json_to_yaml", |s| {
+            let doc = serde_json::from_str(s).map_err(|e| format!("invalid JSON: {e}"))?;
+            to_yaml_string(doc)
+        }))
+    }
+}
+
+/// Check that a YAML stream doesn't expand to more than `MAX_YAML_NODES` nodes
+///
+/// This only looks at parser events, so it is cheap even for inputs that the
+/// loader would expand into gigabytes.
+fn check_yaml_size(s: &str) -> Result<(), String> {
+    let mut parser = Parser::new_from_str(s);
+    let mut total: usize = 0;
+    // Expanded size of each anchor, and the anchor and starting count of each
+    // open collection
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    let mut open: Vec<(usize, usize)> = Vec::new();
+
+    loop {
+        let (event, _) = parser
+            .next_token()
+            .map_err(|e| format!("invalid YAML: {e}"))?;
+        match event {
+            Event::StreamEnd => return Ok(()),
+            Event::Scalar(_, _, anchor, _) => {
+                total += 1;
+                if anchor > 0 {
+                    sizes.insert(anchor, 1);
+                }
+            }
+            Event::SequenceStart(anchor, _) | Event::MappingStart(anchor, _) => {
+                total += 1;
+                open.push((anchor, total));
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((anchor, start)) = open.pop() {
+                    if anchor > 0 {
+                        sizes.insert(anchor, total - start + 1);
+                    }
+                }
+            }
+            Event::Alias(id) => total = total.saturating_add(sizes.get(&id).copied().unwrap_or(1)),
+            _ => (),
+        }
+
+        if total > MAX_YAML_NODES {
+            return Err(format!(
+                "document expands to more than {MAX_YAML_NODES} nodes"
+            ));
+        }
+    }
+}
+
+/// Parse a YAML document
+pub fn parse_yaml(s: &str) -> Result<Value, String> {
+    check_yaml_size(s)?;
+    let mut docs = YamlLoader::load_from_str(s).map_err(|e| format!("invalid YAML: {e}"))?;
+    match docs.len() {
+        0 => Ok(Value::Null),
+        1 => Ok(from_yaml(docs.pop().unwrap())),
+        n => Err(format!("expected a single YAML document but got {n}")),
+    }
+}
+
+/// Convert a YAML value to JSON
+fn from_yaml(value: Yaml) -> Value {
+    match value {
+        Yaml::Real(s) => match s.parse::<Number>() {
+            Ok(num) => Value::Number(num),
+            Err(_) => Yaml::Real(s)
+                .as_f64()
+                .and_then(Number::from_f64)
+                .map_or(Value::Null, Value::Number),
+        },
+        Yaml::Integer(v) => v.into(),
+        Yaml::String(s) => Value::String(s),
+        Yaml::Boolean(v) => Value::Bool(v),
+        Yaml::Array(arr) => Value::Array(arr.into_iter().map(from_yaml).collect()),
+        Yaml::Hash(hash) => Value::Object(
+            hash.into_iter()
+                .map(|(k, v)| (key_string(from_yaml(k)), from_yaml(v)))
+                .collect::<Map<_, _>>(),
+        ),
+        Yaml::Alias(_) | Yaml::Null | Yaml::BadValue => Value::Null,
+    }
+}
+
+/// Parse a TOML document
+pub fn parse_toml(s: &str) -> Result<Value, String> {
+    let table: toml::Table = s.parse().map_err(|e| format!("invalid TOML: {e}"))?;
+    Ok(from_toml(toml::Value::Table(table)))
+}
+
+/// Convert a TOML value to JSON
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(v) => v.into(),
+        toml::Value::Float(v) => Number::from_f64(v).map_or(Value::Null, Value::Number),
+        toml::Value::Boolean(v) => Value::Bool(v),
+        toml::Value::Datetime(dt) => Value::String(dt.to_string()),
+        toml::Value::Array(arr) => Value::Array(arr.into_iter().map(from_toml).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(k, v)| (k, from_toml(v)))
+                .collect::<Map<_, _>>(),
+        ),
+    }
+}
+
+/// Write a JSON value as a YAML document, without the leading `---`
+pub fn to_yaml_string(value: Value) -> Result<String, String> {
+    let mut out = String::new();
+    YamlEmitter::new(&mut out)
+        .dump(&to_yaml(value))
+        .map_err(|e| format!("cannot write YAML: {e}"))?;
+    Ok(out.strip_prefix("---\n").unwrap_or(&out).to_owned())
+}
+
+/// Convert a JSON value to YAML
+fn to_yaml(value: Value) -> Yaml {
+    match value {
+        Value::Null => Yaml::Null,
+        Value::Bool(v) => Yaml::Boolean(v),
+        // Floats are written as their text, so nothing is lost
+        Value::Number(n) => match n.as_i64() {
+            Some(v) => Yaml::Integer(v),
+            None => Yaml::Real(n.to_string()),
+        },
+        Value::String(s) => Yaml::String(s),
+        Value::Array(arr) => Yaml::Array(arr.into_iter().map(to_yaml).collect()),
+        Value::Object(map) => Yaml::Hash(
+            map.into_iter()
+                .map(|(k, v)| (Yaml::String(k), to_yaml(v)))
+                .collect::<Hash>(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_yaml() {
+        let yaml = "
+name: app
+port: 8080
+ratio: 10.50
+debug: false
+limit: .inf
+1: one
+base: &base {a: 1}
+copy: *base
+hosts:
+  - a.example.com
+  - ~
+";
+        assert_eq!(
+            parse_yaml(yaml).unwrap().to_string(),
+            concat!(
+                r#"{"name":"app","port":8080,"ratio":10.50,"debug":false,"limit":null,"#,
+                r#""1":"one","base":{"a":1},"copy":{"a":1},"hosts":["a.example.com",null]}"#
+            )
+        );
+        assert_eq!(parse_yaml("").unwrap(), Value::Null);
+        assert_eq!(
+            parse_yaml("[1.5e3, +1.5]").unwrap().to_string(),
+            "[1.5e+3,1.5]"
+        );
+        assert!(parse_yaml("a: [1").is_err());
+        assert!(parse_yaml("a: 1\n---\nb: 2").is_err());
+    }
+
+    #[test]
+    fn test_yaml_alias_expansion() {
+        // Each level has 10 aliases of the previous one, so this expands to
+        // 10^7 copies of the first value
+        let mut yaml = "l0: &l0 [x, x, x, x, x, x, x, x, x, x]\n".to_owned();
+        for level in 1..=7 {
+            let prev = level - 1;
+            yaml.push_str(&format!("l{level}: &l{level} ["));
+            yaml.push_str(&vec![format!("*l{prev}"); 10].join(", "));
+            yaml.push_str("]\n");
+        }
+        let err = parse_yaml(&yaml).unwrap_err();
+        assert!(err.contains("expands to more than"), "{err}");
+
+        // A few levels are fine
+        let small: String = yaml.lines().take(3).map(|l| format!("{l}\n")).collect();
+        let doc = parse_yaml(&small).unwrap();
+        assert_eq!(doc["l2"].as_array().unwrap().len(), 10);
+        assert_eq!(doc["l2"][9][9][9], json!("x"));
+    }
+
+    #[test]
+    fn test_toml() {
+        let toml = r#"
+title = "cfg"
+[server]
+port = 8080
+ratio = 0.5
+started = 1979-05-27T07:32:00Z
+tags = ["a", "b"]
+"#;
+        assert_eq!(
+            parse_toml(toml).unwrap().to_string(),
+            concat!(
+                r#"{"title":"cfg","server":{"port":8080,"ratio":0.5,"#,
+                r#""started":"1979-05-27T07:32:00Z","tags":["a","b"]}}"#
+            )
+        );
+        assert_eq!(parse_toml("x = nan").unwrap(), json!({"x": null}));
+        assert!(parse_toml("x = ").is_err());
+    }
+
+    #[test]
+    fn test_to_yaml() {
+        let doc: Value =
+            serde_json::from_str(r#"{"b": 1, "a": [true, null, 2.50], "s": "yes", "e": {}}"#)
+                .unwrap();
+        let yaml = to_yaml_string(doc.clone()).unwrap();
+        assert_eq!(
+            yaml,
+            "b: 1\na:\n  - true\n  - ~\n  - 2.50\ns: \"yes\"\ne: {}"
+        );
+        assert_eq!(parse_yaml(&yaml).unwrap(), doc);
+    }
+
+    #[test]
+    fn test_udfs() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![("a: [1, 2]", "text", false)];
+        let mut udf = YamlToJson::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"a":[1,2]}"#)));
+
+        let mut args = mock_args![("a: [1", "text", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![(String None, "text", true)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![("a = 1", "text", false)];
+        let mut udf = TomlToJson::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"a":1}"#)));
+
+        let mut args = mock_args![("[[", "text", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![(r#"{"a": "x"}"#, "doc", false)];
+        let mut udf = JsonToYaml::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some("a: x")));
+
+        let mut args = mock_args![("{", "doc", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+    }
+}
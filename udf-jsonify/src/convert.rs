@@ -0,0 +1,247 @@
+//! Per-argument conversion to JSON values
+//!
+//! An argument's key may start with a prefix that changes how its value is
+//! interpreted:
+//!
+//! - `json:`: the value is JSON text and is embedded as-is, rather than as a
+//!   string
+//! - `base64:`, `hex:`, `extjson:`: the value is binary and is always encoded
+//!   this way, even if it happens to be valid UTF-8. `extjson` produces a
+//!   `{"$binary": ...}` object as used by MongoDB extended JSON.
+//!
+//! It may instead end with a type hint, for types that SQL doesn't have:
+//!
+//! - `:bool`: emit `true` or `false`. Numbers are true if nonzero; strings such
+//!   as `yes`/`no` and `true`/`false` are also recognized.
+//! - `:ts`: `DATE` and `DATETIME` strings are converted to ISO 8601 in UTC,
+//!   e.g. `2023-01-02 03:04:05` becomes `2023-01-02T03:04:05Z`. The zero date is
+//!   emitted as `null`.
+//! - `:split`: split a comma separated string into an array of trimmed strings
+//!
+//! Values that can't be converted as requested are emitted as they would be
+//! without a hint.
+
+use std::fmt::Write;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use udf::prelude::*;
+
+use crate::options::{Binary, InvalidJson, Options};
+use crate::res_to_json_val;
+
+/// How a single argument should be converted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArgKind {
+    /// Use the SQL type to pick a JSON type
+    #[default]
+    Plain,
+    /// Parse the value as JSON
+    Json,
+    /// Always encode the value as binary
+    Binary(Binary),
+    /// Emit a boolean
+    Bool,
+    /// Emit a date or datetime as an ISO 8601 timestamp
+    Timestamp,
+    /// Split a comma separated list into an array
+    Split,
+}
+
+/// Split a known prefix or type hint off of a key, returning the kind and the
+/// remaining key
+pub fn split_kind(key: &str) -> (ArgKind, &str) {
+    if let Some((prefix, rest)) = key.split_once(':') {
+        let kind = match prefix {
+            "json" => Some(ArgKind::Json),
+            "base64" => Some(ArgKind::Binary(Binary::Base64)),
+            "hex" => Some(ArgKind::Binary(Binary::Hex)),
+            "extjson" => Some(ArgKind::Binary(Binary::ExtJson)),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            return (kind, rest);
+        }
+    }
+
+    if let Some((rest, hint)) = key.rsplit_once(':') {
+        let kind = match hint {
+            "bool" => Some(ArgKind::Bool),
+            "ts" => Some(ArgKind::Timestamp),
+            "split" => Some(ArgKind::Split),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            return (kind, rest);
+        }
+    }
+
+    (ArgKind::Plain, key)
+}
+
+/// Convert a single argument's value according to its kind
+pub fn convert_arg(
+    source: SqlResult,
+    kind: ArgKind,
+    opts: &Options,
+) -> Result<Value, ProcessError> {
+    match (kind, &source) {
+        (ArgKind::Json, SqlResult::String(Some(bytes))) => match serde_json::from_slice(bytes) {
+            Ok(v) => Ok(v),
+            Err(e) => match opts.invalid_json {
+                InvalidJson::String => res_to_json_val(source, opts),
+                InvalidJson::Error => {
+                    udf_log!(Warning: "jsonify: argument is not valid JSON: {e}");
+                    Err(ProcessError)
+                }
+            },
+        },
+        (ArgKind::Binary(enc), SqlResult::String(Some(bytes))) => Ok(encode_binary(bytes, enc)),
+        (ArgKind::Bool, _) => to_bool(&source).map_or_else(
+            || res_to_json_val(source.clone(), opts),
+            |v| Ok(Value::Bool(v)),
+        ),
+        (ArgKind::Timestamp, _) => match source.as_string().map(to_iso8601) {
+            Some(Some(ts)) => Ok(ts),
+            _ => res_to_json_val(source, opts),
+        },
+        (ArgKind::Split, _) => match source.as_string() {
+            Some("") => Ok(Value::Array(Vec::new())),
+            Some(s) => Ok(s.split(',').map(|item| Value::from(item.trim())).collect()),
+            None => res_to_json_val(source, opts),
+        },
+        _ => res_to_json_val(source, opts),
+    }
+}
+
+/// Interpret a value as a boolean, if possible
+fn to_bool(source: &SqlResult) -> Option<bool> {
+    match *source {
+        SqlResult::Int(Some(v)) => Some(v != 0),
+        SqlResult::Real(Some(v)) => Some(v != 0.0),
+        SqlResult::Decimal(Some(v)) => v.parse::<f64>().ok().map(|v| v != 0.0),
+        SqlResult::String(Some(v)) => match v.to_ascii_lowercase().as_slice() {
+            b"1" | b"true" | b"t" | b"yes" | b"y" | b"on" => Some(true),
+            b"0" | b"false" | b"f" | b"no" | b"n" | b"off" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Convert `YYYY-MM-DD` or `YYYY-MM-DD HH:MM:SS[.ffffff]` to ISO 8601
+///
+/// Returns `None` if the format is not recognized, and `null` for zero dates.
+fn to_iso8601(s: &str) -> Option<Value> {
+    /// Check that `s` matches `pattern`, where `9` stands for any digit
+    fn matches(s: &[u8], pattern: &[u8]) -> bool {
+        s.len() == pattern.len()
+            && s.iter().zip(pattern).all(|(c, p)| match p {
+                b'9' => c.is_ascii_digit(),
+                _ => c == p,
+            })
+    }
+
+    let bytes = s.as_bytes();
+    let (date, time) = match bytes.len() {
+        10 => (bytes, None),
+        19.. => (&bytes[..10], Some(&bytes[11..])),
+        _ => return None,
+    };
+
+    if !matches(date, b"9999-99-99") {
+        return None;
+    }
+    if date == b"0000-00-00" {
+        return Some(Value::Null);
+    }
+
+    let Some(time) = time else {
+        return Some(Value::String(format!("{s}T00:00:00Z")));
+    };
+
+    let (hms, frac) = time.split_at(8);
+    let sep_ok = matches!(bytes[10], b' ' | b'T');
+    let frac_ok = frac.is_empty()
+        || (frac.len() > 1 && frac[0] == b'.' && frac[1..].iter().all(u8::is_ascii_digit));
+    if !sep_ok || !frac_ok || !matches(hms, b"99:99:99") {
+        return None;
+    }
+
+    Some(Value::String(format!("{}T{}Z", &s[..10], &s[11..])))
+}
+
+/// Encode bytes that should not be interpreted as text
+pub fn encode_binary(bytes: &[u8], enc: Binary) -> Value {
+    match enc {
+        Binary::Lossy => Value::String(String::from_utf8_lossy(bytes).into_owned()),
+        Binary::Base64 => Value::String(BASE64.encode(bytes)),
+        Binary::Hex => {
+            let mut s = String::with_capacity(bytes.len() * 2);
+            for b in bytes {
+                write!(s, "{b:02x}").unwrap();
+            }
+            Value::String(s)
+        }
+        Binary::ExtJson => json!({"$binary": {"base64": BASE64.encode(bytes), "subType": "00"}}),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_kind() {
+        assert_eq!(split_kind("meta"), (ArgKind::Plain, "meta"));
+        assert_eq!(split_kind("json:meta"), (ArgKind::Json, "meta"));
+        assert_eq!(split_kind("json:a.b"), (ArgKind::Json, "a.b"));
+        assert_eq!(split_kind("jsonmeta"), (ArgKind::Plain, "jsonmeta"));
+        assert_eq!(split_kind("hex:id"), (ArgKind::Binary(Binary::Hex), "id"));
+        assert_eq!(split_kind("other:id"), (ArgKind::Plain, "other:id"));
+        assert_eq!(split_kind("active:bool"), (ArgKind::Bool, "active"));
+        assert_eq!(split_kind("a.b:ts"), (ArgKind::Timestamp, "a.b"));
+        assert_eq!(split_kind("x:y:split"), (ArgKind::Split, "x:y"));
+        assert_eq!(split_kind("json:x:bool"), (ArgKind::Json, "x:bool"));
+    }
+
+    #[test]
+    fn test_to_iso8601() {
+        let s = |v: &str| Some(Value::String(v.to_owned()));
+        assert_eq!(to_iso8601("2023-01-02"), s("2023-01-02T00:00:00Z"));
+        assert_eq!(to_iso8601("2023-01-02 03:04:05"), s("2023-01-02T03:04:05Z"));
+        assert_eq!(
+            to_iso8601("2023-01-02 03:04:05.123456"),
+            s("2023-01-02T03:04:05.123456Z")
+        );
+        assert_eq!(to_iso8601("2023-01-02T03:04:05"), s("2023-01-02T03:04:05Z"));
+        assert_eq!(to_iso8601("0000-00-00 00:00:00"), Some(Value::Null));
+        assert_eq!(to_iso8601("0000-00-00"), Some(Value::Null));
+        assert_eq!(to_iso8601("2023-01-02 03:04"), None);
+        assert_eq!(to_iso8601("2023-01-02 03:04:05."), None);
+        assert_eq!(to_iso8601("2023-01-02X03:04:05"), None);
+        assert_eq!(to_iso8601("not a date"), None);
+    }
+
+    #[test]
+    fn test_encode_binary() {
+        let bytes = b"\x00\xffab";
+        assert_eq!(
+            encode_binary(bytes, Binary::Lossy),
+            Value::String("\0\u{fffd}ab".to_owned())
+        );
+        assert_eq!(
+            encode_binary(bytes, Binary::Base64),
+            Value::String("AP9hYg==".to_owned())
+        );
+        assert_eq!(
+            encode_binary(bytes, Binary::Hex),
+            Value::String("00ff6162".to_owned())
+        );
+        assert_eq!(
+            encode_binary(bytes, Binary::ExtJson).to_string(),
+            r#"{"$binary":{"base64":"AP9hYg==","subType":"00"}}"#
+        );
+    }
+}
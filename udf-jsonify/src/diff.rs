@@ -0,0 +1,342 @@
+//! Compute an RFC 6902 JSON Patch that turns one document into another
+//!
+//! Objects are compared key by key and arrays are compared by index, so
+//! inserting at the start of an array replaces every following item. If an
+//! identity key is given and every item of both arrays is an object with a
+//! unique value for that key, items are matched by that key instead and the
+//! patch uses `move`, `add` and `remove` to reorder them. Arrays that don't
+//! meet those conditions fall back to index comparison.
+//!
+//! Numbers are compared as exact decimals, so `1` and `1.0` are equal but IDs
+//! beyond the range of a double or `DECIMAL`s that differ in their last digit
+//! are not.
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::{json, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+use crate::parse_json_arg;
+use crate::patch::json_eq;
+
+/// Create a patch from `a` to `b`
+#[derive(Debug, Default)]
+struct JsonDiff(String);
+
+#[register]
+impl BasicUdf for JsonDiff {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_diff(a, b)` or `json_diff(a, b, id_key)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(format!(
+                "json_diff takes 2 or 3 arguments but got {} (usage: `json_diff(a, b)` or \
+                `json_diff(a, b, id_key)`)",
+                args.len()
+            ));
+        }
+        for mut arg in args {
+            arg.set_type_coercion(SqlType::String);
+        }
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        cfg.set_maybe_null(true);
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let (Some(a), Some(b)) = (
+            parse_json_arg(args.get(0).unwrap().value(), "json_diff"),
+            parse_json_arg(args.get(1).unwrap().value(), "json_diff"),
+        ) else {
+            return Ok(None);
+        };
+        let id_arg = args.get(2).map(|arg| arg.value());
+        let id_key = id_arg.as_ref().and_then(SqlResult::as_string);
+
+        self.0 = Value::Array(diff(&a, &b, id_key)).to_string();
+        Ok(Some(&self.0))
+    }
+}
+
+/// Create the list of operations that turns `a` into `b`
+pub fn diff(a: &Value, b: &Value, id_key: Option<&str>) -> Vec<Value> {
+    let mut ops = Vec::new();
+    diff_at(a, b, "", id_key, &mut ops);
+    ops
+}
+
+/// Append a reference token to a JSON pointer, escaping as needed
+fn push_token(path: &str, tok: &str) -> String {
+    format!("{path}/{}", tok.replace('~', "~0").replace('/', "~1"))
+}
+
+fn diff_at(a: &Value, b: &Value, path: &str, id_key: Option<&str>, ops: &mut Vec<Value>) {
+    if json_eq(a, b) {
+        return;
+    }
+
+    match (a, b) {
+        (Value::Object(a_map), Value::Object(b_map)) => {
+            for key in a_map.keys().filter(|k| !b_map.contains_key(*k)) {
+                ops.push(json!({"op": "remove", "path": push_token(path, key)}));
+            }
+            for (key, b_val) in b_map {
+                let child = push_token(path, key);
+                match a_map.get(key) {
+                    Some(a_val) => diff_at(a_val, b_val, &child, id_key, ops),
+                    None => ops.push(json!({"op": "add", "path": child, "value": b_val})),
+                }
+            }
+        }
+        (Value::Array(a_arr), Value::Array(b_arr)) => {
+            let by_id =
+                id_key.and_then(|key| Some((key, item_ids(a_arr, key)?, item_ids(b_arr, key)?)));
+            match by_id {
+                Some((key, a_ids, b_ids)) => {
+                    diff_by_id(a_arr, b_arr, &a_ids, &b_ids, path, key, ops);
+                }
+                None => diff_by_index(a_arr, b_arr, path, id_key, ops),
+            }
+        }
+        _ => ops.push(json!({"op": "replace", "path": path, "value": b})),
+    }
+}
+
+fn diff_by_index(
+    a_arr: &[Value],
+    b_arr: &[Value],
+    path: &str,
+    id_key: Option<&str>,
+    ops: &mut Vec<Value>,
+) {
+    for (i, (a_val, b_val)) in a_arr.iter().zip(b_arr).enumerate() {
+        diff_at(a_val, b_val, &format!("{path}/{i}"), id_key, ops);
+    }
+    // Remove from the end so earlier indices stay valid
+    for i in (b_arr.len()..a_arr.len()).rev() {
+        ops.push(json!({"op": "remove", "path": format!("{path}/{i}")}));
+    }
+    for (i, b_val) in b_arr.iter().enumerate().skip(a_arr.len()) {
+        ops.push(json!({"op": "add", "path": format!("{path}/{i}"), "value": b_val}));
+    }
+}
+
+/// Get the identity of every item in an array, or `None` if any item is not an
+/// object with the key or if identities are not unique
+fn item_ids(arr: &[Value], key: &str) -> Option<Vec<String>> {
+    let ids: Vec<String> = arr
+        .iter()
+        .map(|item| item.as_object()?.get(key).map(Value::to_string))
+        .collect::<Option<_>>()?;
+
+    let mut sorted: Vec<_> = ids.iter().collect();
+    sorted.sort_unstable();
+    sorted.dedup();
+    (sorted.len() == ids.len()).then_some(ids)
+}
+
+fn diff_by_id(
+    a_arr: &[Value],
+    b_arr: &[Value],
+    a_ids: &[String],
+    b_ids: &[String],
+    path: &str,
+    id_key: &str,
+    ops: &mut Vec<Value>,
+) {
+    let a_by_id: HashMap<&str, &Value> = a_ids.iter().map(String::as_str).zip(a_arr).collect();
+    let b_has: HashSet<&str> = b_ids.iter().map(String::as_str).collect();
+
+    // Track the array's order of ids as the patch modifies it
+    let mut cur: Vec<&str> = a_ids.iter().map(String::as_str).collect();
+
+    for i in (0..cur.len()).rev() {
+        if !b_has.contains(cur[i]) {
+            ops.push(json!({"op": "remove", "path": format!("{path}/{i}")}));
+            cur.remove(i);
+        }
+    }
+
+    // Everything before `i` is already in place, so an item that needs to move
+    // is always found after `i`
+    for (i, (id, b_val)) in b_ids.iter().zip(b_arr).enumerate() {
+        let item_path = format!("{path}/{i}");
+        match cur.iter().position(|c| c == id) {
+            Some(pos) => {
+                if pos != i {
+                    ops.push(json!({
+                        "op": "move",
+                        "from": format!("{path}/{pos}"),
+                        "path": item_path
+                    }));
+                    let moved = cur.remove(pos);
+                    cur.insert(i, moved);
+                }
+                diff_at(a_by_id[id.as_str()], b_val, &item_path, Some(id_key), ops);
+            }
+            None => {
+                ops.push(json!({"op": "add", "path": item_path, "value": b_val}));
+                cur.insert(i, id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udf::mock::*;
+
+    use super::*;
+    use crate::patch::apply_patch;
+
+    /// Check that the diff is as expected and actually applies
+    fn check(a: Value, b: Value, id_key: Option<&str>, expected: Value) {
+        let ops = Value::Array(diff(&a, &b, id_key));
+        assert_eq!(ops, expected);
+        assert_eq!(apply_patch(a, &ops).unwrap(), b);
+    }
+
+    #[test]
+    fn test_diff_objects() {
+        // Numbers are compared by value
+        assert!(diff(&json!({"a": 1}), &json!({"a": 1.0}), None).is_empty());
+        check(
+            json!({"a": 1, "b": {"c": 2, "d": 3}, "x/y": 0}),
+            json!({"b": {"c": 2, "d": 4}, "e": [1], "x/y": 1}),
+            None,
+            json!([
+                {"op": "remove", "path": "/a"},
+                {"op": "replace", "path": "/b/d", "value": 4},
+                {"op": "add", "path": "/e", "value": [1]},
+                {"op": "replace", "path": "/x~1y", "value": 1},
+            ]),
+        );
+        check(
+            json!({"a": 1}),
+            json!([1]),
+            None,
+            json!([{"op": "replace", "path": "", "value": [1]}]),
+        );
+    }
+
+    #[test]
+    fn test_diff_numbers() {
+        let parse = |s: &str| serde_json::from_str::<Value>(s).unwrap();
+        for (a, b) in [
+            ("1234567890123456789", "1234567890123456788"),
+            ("9007199254740993", "9007199254740992"),
+            ("10.000000000000000001", "10"),
+            ("0.1000000000000000000001", "0.1"),
+        ] {
+            let doc_a = parse(&format!(r#"{{"id": {a}}}"#));
+            let doc_b = parse(&format!(r#"{{"id": {b}}}"#));
+            let expected = parse(&format!(
+                r#"[{{"op": "replace", "path": "/id", "value": {b}}}]"#
+            ));
+            check(doc_a, doc_b, None, expected);
+        }
+        assert!(diff(&parse("[1.50, 2e3]"), &parse("[1.5, 2000]"), None).is_empty());
+    }
+
+    #[test]
+    fn test_diff_arrays_by_index() {
+        check(
+            json!([1, 2, 3, 4]),
+            json!([1, 5]),
+            None,
+            json!([
+                {"op": "replace", "path": "/1", "value": 5},
+                {"op": "remove", "path": "/3"},
+                {"op": "remove", "path": "/2"},
+            ]),
+        );
+        check(
+            json!([1]),
+            json!([1, 2, 3]),
+            None,
+            json!([
+                {"op": "add", "path": "/1", "value": 2},
+                {"op": "add", "path": "/2", "value": 3},
+            ]),
+        );
+    }
+
+    #[test]
+    fn test_diff_arrays_by_id() {
+        let a = json!([
+            {"id": 1, "v": "a"},
+            {"id": 2, "v": "b"},
+            {"id": 3, "v": "c"},
+            {"id": 4, "v": "d"},
+        ]);
+        let b = json!([
+            {"id": 5, "v": "e"},
+            {"id": 3, "v": "c"},
+            {"id": 1, "v": "A"},
+            {"id": 4, "v": "d"},
+        ]);
+        check(
+            a.clone(),
+            b.clone(),
+            Some("id"),
+            json!([
+                {"op": "remove", "path": "/1"},
+                {"op": "add", "path": "/0", "value": {"id": 5, "v": "e"}},
+                {"op": "move", "from": "/2", "path": "/1"},
+                {"op": "replace", "path": "/2/v", "value": "A"},
+            ]),
+        );
+
+        // Without the key every item differs
+        let by_index = diff(&a, &b, None);
+        assert_eq!(by_index.len(), 6);
+        assert_eq!(apply_patch(a, &Value::Array(by_index)).unwrap(), b);
+
+        // Duplicate or missing keys fall back to index comparison
+        check(
+            json!([{"id": 1}, {"id": 1}]),
+            json!([{"id": 1}]),
+            Some("id"),
+            json!([{"op": "remove", "path": "/1"}]),
+        );
+        check(
+            json!([{"id": 1}, 2]),
+            json!([2, {"id": 1}]),
+            Some("id"),
+            json!([
+                {"op": "replace", "path": "/0", "value": 2},
+                {"op": "replace", "path": "/1", "value": {"id": 1}},
+            ]),
+        );
+    }
+
+    #[test]
+    fn test_udf() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![
+            (r#"{"a": [{"k": "x"}, {"k": "y"}]}"#, "a", false),
+            (r#"{"a": [{"k": "y"}, {"k": "x"}]}"#, "b", false),
+            ("k", "id", false)
+        ];
+        let mut udf = JsonDiff::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(r#"[{"op":"move","from":"/a/1","path":"/a/0"}]"#))
+        );
+
+        let mut args = mock_args![("[1]", "a", false), (String None, "b", true)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![("[1]", "a", false)];
+        assert!(JsonDiff::init(cfg.as_init(), args.as_init()).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! Converting between nested documents and flat objects of path keys
+//!
+//! `json_flatten` turns `{"a": {"b": [1, 2]}}` into `{"a.b[0]": 1, "a.b[1]": 2}`,
+//! and `json_unflatten` reverses it using the same path syntax as `jsonify`
+//! aliases. Both accept an optional separator to use instead of `.`.
+//!
+//! Empty objects and arrays are kept as values so that they survive a round
+//! trip. Keys are not escaped, so keys that contain the separator or `[` will
+//! not unflatten to the same document. Keys that are not valid paths are kept
+//! as-is by `json_unflatten`. Array indices must be roughly dense: indices
+//! that would create many more array items than there are keys are an error.
+//!
+//! Errors, including invalid JSON and an empty separator, return `NULL` with a
+//! warning.
+
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+use crate::options::Duplicates;
+use crate::parse_json_arg;
+use crate::template::{parse_path_with, Segment, Template};
+
+/// Default separator between object keys
+const DEFAULT_SEP: &str = ".";
+
+/// Check for `name(doc)` or `name(doc, separator)`
+fn init_doc_sep(cfg: &UdfCfg<Init>, args: &ArgList<Init>, name: &str) -> Result<(), String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err(format!(
+            "{name} takes 1 or 2 arguments but got {} (usage: `{name}(doc)` or \
+            `{name}(doc, separator)`)",
+            args.len()
+        ));
+    }
+    for mut arg in args {
+        arg.set_type_coercion(SqlType::String);
+    }
+    cfg.set_max_len(MaxLenOptions::Blob as u64);
+    cfg.set_maybe_null(true);
+    Ok(())
+}
+
+/// Get the document and separator, or `None` if either is `NULL`
+///
+/// Invalid arguments are logged and also return `None`.
+fn doc_and_sep<'a>(args: &'a ArgList<Process>, name: &str) -> Option<(Value, SqlResult<'a>)> {
+    let doc = parse_json_arg(args.get(0).unwrap().value(), name)?;
+    let sep = match args.get(1) {
+        Some(arg) => arg.value(),
+        None => SqlResult::String(Some(DEFAULT_SEP.as_bytes())),
+    };
+    match sep.as_string()? {
+        "" => {
+            udf_log!(Warning: "{name}: separator may not be empty");
+            None
+        }
+        _ => Some((doc, sep)),
+    }
+}
+
+/// Flatten a document
+#[derive(Debug, Default)]
+struct JsonFlatten(String);
+
+#[register]
+impl BasicUdf for JsonFlatten {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_flatten(doc)` or `json_flatten(doc, separator)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_doc_sep(cfg, args, "json_flatten")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let Some((doc, sep)) = doc_and_sep(args, "json_flatten") else {
+            return Ok(None);
+        };
+        self.0 = Value::Object(flatten(doc, sep.as_string().unwrap())).to_string();
+        Ok(Some(&self.0))
+    }
+}
+
+/// Unflatten a document
+#[derive(Debug, Default)]
+struct JsonUnflatten(String);
+
+#[register]
+impl BasicUdf for JsonUnflatten {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_unflatten(doc)` or `json_unflatten(doc, separator)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_doc_sep(cfg, args, "json_unflatten")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let Some((doc, sep)) = doc_and_sep(args, "json_unflatten") else {
+            return Ok(None);
+        };
+        match unflatten(doc, sep.as_string().unwrap()) {
+            Ok(res) => self.0 = res.to_string(),
+            Err(e) => {
+                udf_log!(Warning: "json_unflatten: {e}");
+                return Ok(None);
+            }
+        }
+        Ok(Some(&self.0))
+    }
+}
+
+/// Flatten a document into a single object
+pub fn flatten(doc: Value, sep: &str) -> Map<String, Value> {
+    let mut ret = Map::new();
+    match doc {
+        Value::Object(map) if map.is_empty() => (),
+        Value::Array(arr) if arr.is_empty() => (),
+        doc => flatten_into(doc, String::new(), sep, &mut ret),
+    }
+    ret
+}
+
+fn flatten_into(value: Value, prefix: String, sep: &str, out: &mut Map<String, Value>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, val) in map {
+                let child = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}{sep}{key}")
+                };
+                flatten_into(val, child, sep, out);
+            }
+        }
+        Value::Array(arr) if !arr.is_empty() => {
+            for (i, val) in arr.into_iter().enumerate() {
+                flatten_into(val, format!("{prefix}[{i}]"), sep, out);
+            }
+        }
+        value => {
+            out.insert(prefix, value);
+        }
+    }
+}
+
+/// Make sure that the arrays described by `paths` stay proportional to the
+/// input
+///
+/// Indices come from row data rather than from aliases, so a short key like
+/// `a[65535]` could otherwise create a huge array. Every slot of a dense array
+/// holds at least one key, so the total number of slots may not exceed the
+/// number of index segments plus the number of keys, which leaves room for a
+/// few gaps.
+fn check_array_slots(paths: &[Vec<Segment>]) -> Result<(), String> {
+    // Length of each array, keyed by the path that leads to it
+    let mut lengths: HashMap<&[Segment], usize> = HashMap::new();
+    let mut index_count = 0;
+
+    for path in paths {
+        for (pos, seg) in path.iter().enumerate() {
+            if let Segment::Index(idx) = seg {
+                index_count += 1;
+                let len = lengths.entry(&path[..pos]).or_default();
+                *len = (*len).max(idx + 1);
+            }
+        }
+    }
+
+    let limit = index_count + paths.len();
+    let total: usize = lengths.values().sum();
+    if total > limit {
+        return Err(format!(
+            "array indices would create {total} array items from {} keys; \
+            at most {limit} are allowed",
+            paths.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Expand an object of path keys into a nested document
+pub fn unflatten(doc: Value, sep: &str) -> Result<Value, String> {
+    let Value::Object(map) = doc else {
+        return Err("argument must be a JSON object".to_owned());
+    };
+
+    let paths: Vec<_> = map
+        .keys()
+        .map(|key| parse_path_with(key, sep).unwrap_or_else(|| vec![Segment::Key(key.clone())]))
+        .collect();
+    check_array_slots(&paths)?;
+
+    let mut tpl = Template::default();
+    let mut values = Vec::with_capacity(map.len());
+    for (idx, ((key, val), path)) in map.into_iter().zip(&paths).enumerate() {
+        tpl.insert_path(path, &key, idx, Duplicates::Last)?;
+        values.push(val);
+    }
+
+    tpl.build(|idx| Ok(Some(std::mem::take(&mut values[idx]))))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_flatten() {
+        let doc = json!({
+            "a": {"b": [1, {"c": null}], "d": "x"},
+            "e": [],
+            "f": {},
+            "g": [[true]]
+        });
+        let flat = json!({
+            "a.b[0]": 1,
+            "a.b[1].c": null,
+            "a.d": "x",
+            "e": [],
+            "f": {},
+            "g[0][0]": true
+        });
+        assert_eq!(Value::Object(flatten(doc.clone(), ".")), flat);
+        assert_eq!(unflatten(flat, ".").unwrap(), doc);
+
+        let flat = json!({
+            "a__b[0]": 1,
+            "a__b[1]__c": null,
+            "a__d": "x",
+            "e": [],
+            "f": {},
+            "g[0][0]": true
+        });
+        assert_eq!(Value::Object(flatten(doc.clone(), "__")), flat);
+        assert_eq!(unflatten(flat, "__").unwrap(), doc);
+
+        assert_eq!(Value::Object(flatten(json!({}), ".")), json!({}));
+        assert_eq!(Value::Object(flatten(json!(5), ".")), json!({"": 5}));
+    }
+
+    #[test]
+    fn test_unflatten() {
+        assert_eq!(
+            unflatten(json!({"x[2]": 1, "bad..key": 2, "y.z": 3}), ".").unwrap(),
+            json!({"x": [null, null, 1], "bad..key": 2, "y": {"z": 3}})
+        );
+        assert!(unflatten(json!({"a": 1, "a.b": 2}), ".").is_err());
+        assert!(unflatten(json!([1]), ".").is_err());
+    }
+
+    #[test]
+    fn test_unflatten_sparse_indices() {
+        // Each key alone would create an array of 65536 items
+        let doc: Map<String, Value> = (0..200)
+            .map(|i| (format!("k{i}[65535]"), json!(1)))
+            .collect();
+        let err = unflatten(Value::Object(doc), ".").unwrap_err();
+        assert!(err.contains("at most 400"), "{err}");
+
+        assert!(unflatten(json!({"a[0]": 1, "a[5]": 2}), ".").is_err());
+
+        // Dense nested arrays are fine
+        let doc = json!([[1, 2], [3, [4, 5]]]);
+        let flat = Value::Object(flatten(json!({"x": doc}), "."));
+        assert_eq!(unflatten(flat, ".").unwrap(), json!({"x": doc}));
+    }
+
+    #[test]
+    fn test_udfs() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(r#"{"a": {"b": 1.50}}"#, "doc", false)];
+        let mut udf = JsonFlatten::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"a.b":1.50}"#)));
+
+        let mut args = mock_args![
+            (r#"{"a/b": 1, "a/c[0]": 2}"#, "doc", false),
+            ("/", "sep", false)
+        ];
+        let mut udf = JsonUnflatten::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"a":{"b":1,"c":[2]}}"#)));
+
+        let mut args = mock_args![("{}", "doc", false), ("", "sep", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![(r#"{"a[9]": 1}"#, "doc", false), ("/", "sep", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![("{}", "doc", false), (String None, "sep", true)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+    }
+}
@@ -1,58 +1,347 @@
-//! Adds the `jsonify` function to turn any arguments into a JSON string
+//! Adds the `jsonify` function to turn any arguments into a JSON string, and
+//! `jsonify_array` to turn them into a JSON array. `jsonify_agg` is an
+//! aggregate version of `jsonify` that creates an array of objects, and
+//! `jsonify_lines` creates JSON Lines with one object per row.
+//! `json_object_agg` aggregates key/value pairs into a single object.
+//! `json_merge_patch` and `json_patch` modify existing documents using RFC 7386
+//! merge patches and RFC 6902 JSON Patch operations, and `json_diff` creates
+//! the JSON Patch between two documents. `json_query` and `json_query_scalar`
+//! extract data with JMESPath expressions. `json_schema_valid` and
+//! `json_schema_errors` validate documents against a JSON Schema.
+//! `json_flatten` and `json_unflatten` convert between nested documents and
+//! flat objects with path keys. `msgpackify` and `cborify` encode rows as
+//! MessagePack or CBOR, and `msgpack_to_json` and `cbor_to_json` decode them.
+//! `yaml_to_json`, `toml_to_json` and `json_to_yaml` convert between JSON and
+//! configuration formats. `urlencodify` creates a URL query string and
+//! `querystring_to_json` parses one.
 //!
 //! Add with
 //!
 //! ```sql
 //! CREATE FUNCTION jsonify RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION jsonify_sparse RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION jsonify_pretty RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION jsonify_canonical RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_canonicalize RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION jsonify_array RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE AGGREGATE FUNCTION jsonify_agg RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE AGGREGATE FUNCTION json_object_agg RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE AGGREGATE FUNCTION jsonify_lines RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_merge_patch RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_patch RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_diff RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_query RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_query_scalar RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_schema_valid RETURNS integer SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_schema_errors RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_flatten RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_unflatten RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION msgpackify RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION cborify RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION msgpack_to_json RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION cbor_to_json RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION yaml_to_json RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION toml_to_json RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION json_to_yaml RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION urlencodify RETURNS string SONAME 'libudf_jsonify.so';
+//! CREATE FUNCTION querystring_to_json RETURNS string SONAME 'libudf_jsonify.so';
 //! ```
+//!
+//! Keys that look like paths build nested values, so
+//! `jsonify(a AS "user.name", b AS "tags[0]")` produces
+//! `{"user":{"name":...},"tags":[...]}`.
+//!
+//! Arguments that already contain JSON (e.g. a `JSON` column or a nested
+//! `jsonify` call) can be embedded as values rather than strings by prefixing
+//! their key with `json:`, as in `jsonify(meta AS "json:meta")`.
+//!
+//! Strings that are not valid UTF-8 are base64 encoded by default. Binary
+//! arguments can also be marked with a `base64:`, `hex:` or `extjson:` prefix
+//! to always encode them.
+//!
+//! Keys can end with a type hint: `:bool` to emit a boolean, `:ts` to emit a
+//! `DATETIME` as an ISO 8601 timestamp, or `:split` to split a comma separated
+//! string into an array.
+//!
+//! `jsonify_sparse` is the same as `jsonify`, but leaves out keys with `NULL`
+//! values. `jsonify_pretty` pretty prints its output, and `jsonify_canonical`
+//! creates canonical JSON (RFC 8785) that is suitable for hashing and signing.
+//! `json_canonicalize` canonicalizes an existing JSON document.
+//!
+//! `DECIMAL` values are emitted exactly as the server provides them, so no
+//! precision is lost.
+//!
+//! Keys are emitted in argument order by default. Behavior can be changed with a
+//! leading options argument, e.g. `jsonify('sorted' AS "$opts", a, b)`; see
+//! `options.rs` for everything that is available.
+
+mod agg;
+mod canonical;
+mod config;
+mod convert;
+mod diff;
+mod flatten;
+mod options;
+mod output;
+mod packed;
+mod patch;
+mod query;
+mod schema;
+mod template;
+mod urlencoded;
 
-use serde_json::{Map, Number, Value};
+use serde_json::{Number, Value};
 use udf::prelude::*;
 use udf::MaxLenOptions;
 
+use crate::convert::{convert_arg, encode_binary, split_kind, ArgKind};
+use crate::options::{Decimals, NonFinite, Nulls, Options};
+use crate::output::to_json_string;
+use crate::template::Template;
+
 #[derive(Default, Debug)]
-struct Jsonify(String);
+struct Jsonify {
+    spec: ObjectSpec,
+    res: String,
+}
 
 #[register]
 impl BasicUdf for Jsonify {
-    type Returns<'a> = &'a str;
+    type Returns<'a> = Option<&'a str>;
+
+    /// Build our output template and set our maximum length
+    fn init<'a>(cfg: &UdfCfg<Init>, args: &'a ArgList<'a, Init>) -> Result<Self, String> {
+        Self::with_defaults(cfg, args, Options::default())
+    }
+
+    /// All we do here is slot our arguments into the template
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        // Conversion errors have already been logged
+        let Ok(json) = self.spec.build(args) else {
+            return Ok(None);
+        };
+        self.res = to_json_string(&json, &self.spec.opts);
+        Ok(Some(&self.res))
+    }
+}
 
-    /// Just set our maximum length here
-    fn init<'a>(cfg: &UdfCfg<Init>, _args: &'a ArgList<'a, Init>) -> Result<Self, String> {
+impl Jsonify {
+    /// Shared `init` for `jsonify` and its variants, which only differ in
+    /// their default options
+    fn with_defaults(
+        cfg: &UdfCfg<Init>,
+        args: &ArgList<Init>,
+        defaults: Options,
+    ) -> Result<Self, String> {
+        let spec = ObjectSpec::from_args(args, defaults)?;
         cfg.set_max_len(MaxLenOptions::Blob as u64);
-        Ok(Self::default())
+        cfg.set_maybe_null(spec.opts.may_fail());
+        Ok(Self {
+            spec,
+            res: String::new(),
+        })
+    }
+}
+
+/// `jsonify` that leaves out `NULL` values by default
+#[derive(Default, Debug)]
+struct JsonifySparse(Jsonify);
+
+#[register]
+impl BasicUdf for JsonifySparse {
+    type Returns<'a> = Option<&'a str>;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let defaults = Options {
+            nulls: Nulls::Omit,
+            ..Default::default()
+        };
+        Jsonify::with_defaults(cfg, args, defaults).map(Self)
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        self.0.process(cfg, args, error)
+    }
+}
+
+/// `jsonify` with pretty printed output
+#[derive(Default, Debug)]
+struct JsonifyPretty(Jsonify);
+
+#[register]
+impl BasicUdf for JsonifyPretty {
+    type Returns<'a> = Option<&'a str>;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let defaults = Options {
+            indent: Some(2),
+            ..Default::default()
+        };
+        Jsonify::with_defaults(cfg, args, defaults).map(Self)
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        self.0.process(cfg, args, error)
+    }
+}
+
+/// Positional version of `jsonify`, creates an array of its arguments
+#[derive(Default, Debug)]
+struct JsonifyArray {
+    opts: Options,
+    /// Number of leading arguments used for options
+    skip: usize,
+    res: String,
+}
+
+#[register]
+impl BasicUdf for JsonifyArray {
+    type Returns<'a> = Option<&'a str>;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let (opts, skip) = Options::from_args(args, Options::default())?;
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        cfg.set_maybe_null(opts.may_fail());
+        Ok(Self {
+            opts,
+            skip,
+            res: String::new(),
+        })
     }
 
-    /// All we do here is map our arguments,
     fn process<'a>(
         &'a mut self,
         _cfg: &UdfCfg<Process>,
         args: &ArgList<Process>,
         _error: Option<NonZeroU8>,
     ) -> Result<Self::Returns<'a>, ProcessError> {
-        let json_map: Map<String, Value> = args
+        let items = args
             .iter()
-            .map(|a| (a.attribute().to_owned(), res_to_json_val(a.value())))
-            .collect();
-        self.0 = Value::Object(json_map).to_string();
-        Ok(&self.0)
+            .skip(self.skip)
+            .map(|a| res_to_json_val(a.value(), &self.opts))
+            .collect::<Result<_, _>>();
+        // Conversion errors have already been logged
+        let Ok(items) = items else {
+            return Ok(None);
+        };
+        self.res = to_json_string(&Value::Array(items), &self.opts);
+        Ok(Some(&self.res))
     }
 }
 
+/// Everything needed to turn a row of arguments into an object, shared by
+/// `jsonify` and its relatives
+#[derive(Debug, Default)]
+struct ObjectSpec {
+    opts: Options,
+    /// Output layout, built from argument attributes
+    template: Template,
+    /// How to convert each argument, indexed the same as the arguments
+    kinds: Vec<ArgKind>,
+}
+
+impl ObjectSpec {
+    /// Read options and argument names to create the spec
+    fn from_args(args: &ArgList<Init>, defaults: Options) -> Result<Self, String> {
+        let (opts, skip) = Options::from_args(args, defaults)?;
+        let mut template = Template::default();
+        let mut kinds = vec![ArgKind::default(); args.len()];
+
+        for (idx, arg) in args.iter().enumerate().skip(skip) {
+            let (kind, key) = split_kind(arg.attribute());
+            kinds[idx] = kind;
+            template.insert(key, idx, !opts.flat, opts.duplicates)?;
+        }
+
+        if opts.sorted {
+            template.sort();
+        }
+
+        Ok(Self {
+            opts,
+            template,
+            kinds,
+        })
+    }
+
+    /// Fill the template with the values of the current row
+    fn build(&self, args: &ArgList<Process>) -> Result<Value, ProcessError> {
+        self.template.build(|idx| self.convert(args, idx))
+    }
+
+    /// Convert the argument at `idx`, or return `None` if it should be left out
+    fn convert(&self, args: &ArgList<Process>, idx: usize) -> Result<Option<Value>, ProcessError> {
+        let arg = args.get(idx).ok_or(ProcessError)?;
+        let value = convert_arg(arg.value(), self.kinds[idx], &self.opts)?;
+        if value.is_null() && self.opts.nulls == Nulls::Omit {
+            Ok(None)
+        } else {
+            Ok(Some(value))
+        }
+    }
+}
+
+/// Parse an argument that should contain a JSON document
+///
+/// Returns `None` if the argument is `NULL`, or if it is not valid JSON, in
+/// which case a warning is logged.
+fn parse_json_arg(source: SqlResult, fn_name: &str) -> Option<Value> {
+    let bytes = source.as_bytes()?;
+    serde_json::from_slice(bytes)
+        .map_err(|e| udf_log!(Warning: "{fn_name}: argument is not valid JSON: {e}"))
+        .ok()
+}
+
 /// Convert a `SqlResult` to a `serde_json::Value`
 ///
 /// Return the apropriate type if possible (`String` or `Number`), `Null`
-/// otherwise
-fn res_to_json_val(source: SqlResult) -> Value {
-    match source {
-        SqlResult::String(Some(v)) => Value::String(String::from_utf8_lossy(v).into_owned()),
-        SqlResult::Real(Some(v)) => Number::from_f64(v)
-            .map(Value::Number)
-            .unwrap_or(Value::Null),
+/// otherwise. Decimals are kept exactly as the server formats them; they are
+/// emitted as strings if `opts` asks for it, or if they aren't valid JSON
+/// numbers. Strings that aren't UTF-8 are encoded as set in `opts`.
+///
+/// Only fails if `opts` says that NaN or infinite floats are an error.
+fn res_to_json_val(source: SqlResult, opts: &Options) -> Result<Value, ProcessError> {
+    let ret = match source {
+        SqlResult::String(Some(v)) => match std::str::from_utf8(v) {
+            Ok(s) => Value::String(s.to_owned()),
+            Err(_) => encode_binary(v, opts.binary),
+        },
+        SqlResult::Real(Some(v)) => match (Number::from_f64(v), opts.nonfinite) {
+            (Some(num), _) => Value::Number(num),
+            (None, NonFinite::Null) => Value::Null,
+            (None, NonFinite::String) if v.is_nan() => Value::String("NaN".to_owned()),
+            (None, NonFinite::String) if v > 0.0 => Value::String("Infinity".to_owned()),
+            (None, NonFinite::String) => Value::String("-Infinity".to_owned()),
+            (None, NonFinite::Error) => {
+                udf_log!(Warning: "jsonify: cannot represent {v} in JSON");
+                return Err(ProcessError);
+            }
+        },
         SqlResult::Int(Some(v)) => Value::Number(v.into()),
-        SqlResult::Decimal(Some(v)) => v.parse().map(Value::Number).unwrap_or(Value::Null),
+        SqlResult::Decimal(Some(v)) => match opts.decimals {
+            Decimals::Number => v
+                .parse()
+                .map_or_else(|_| Value::String(v.to_owned()), Value::Number),
+            Decimals::String => Value::String(v.to_owned()),
+        },
         _ => Value::Null,
-    }
+    };
+    Ok(ret)
 }
 
 #[cfg(test)]
@@ -71,7 +360,7 @@ mod tests {
         let expected = r#"{}"#;
 
         let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
-        assert_eq!(res, Ok(expected));
+        assert_eq!(res, Ok(Some(expected)));
     }
 
     #[test]
@@ -90,20 +379,376 @@ mod tests {
         ];
 
         let mut s = String::from("{");
-        s.push_str(r#""decimal":1234.56,"#);
+        s.push_str(r#""empty_string":null,"#);
+        s.push_str(r#""empty_int":null,"#);
         s.push_str(r#""empty_decimal":null,"#);
         s.push_str(r#""empty_float":null,"#);
-        s.push_str(r#""empty_int":null,"#);
-        s.push_str(r#""empty_string":null,"#);
-        s.push_str(r#""float":111.001,"#);
+        s.push_str(r#""string":"some string","#);
         s.push_str(r#""int":500,"#);
         s.push_str(r#""neg_int":-500,"#);
-        s.push_str(r#""string":"some string""#);
+        s.push_str(r#""decimal":1234.56,"#);
+        s.push_str(r#""float":111.001"#);
         s.push('}');
 
         let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
         let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
 
-        assert_eq!(res, Ok(s.as_str()));
+        assert_eq!(res, Ok(Some(s.as_str())));
+    }
+
+    #[test]
+    fn test_sorted() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            ("sorted", "$opts", false),
+            (1, "id", false),
+            ("foo", "name", false),
+            ("2023-01-01", "created", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+
+        assert_eq!(
+            res,
+            Ok(Some(r#"{"created":"2023-01-01","id":1,"name":"foo"}"#))
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut cfg = MockUdfCfg::new();
+        // The last value wins by default
+        let mut arglist = mock_args![(1, "id", false), ("foo", "name", false), (2, "id", false)];
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"id":2,"name":"foo"}"#)));
+
+        let mut arglist = mock_args![
+            ("duplicates=error", "$opts", false),
+            (1, "id", false),
+            ("foo", "name", false),
+            (2, "id", false),
+        ];
+        let res = Jsonify::init(cfg.as_init(), arglist.as_init());
+        assert_eq!(
+            res.unwrap_err(),
+            "duplicate key 'id'; use an alias or the 'duplicates' option"
+        );
+
+        let mut arglist = mock_args![
+            ("duplicates=first", "$opts", false),
+            (1, "id", false),
+            ("foo", "name", false),
+            (2, "id", false),
+        ];
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"id":1,"name":"foo"}"#)));
+
+        let mut arglist = mock_args![
+            ("duplicates=last", "$opts", false),
+            (1, "id", false),
+            ("foo", "name", false),
+            (2, "id", false),
+        ];
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"id":2,"name":"foo"}"#)));
+    }
+
+    #[test]
+    fn test_nested() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            ("foo", "user.name", false),
+            (1, "user.id", false),
+            (Decimal "10.50", "order.total", false),
+            ("a", "tags[0]", false),
+            ("b", "tags[1]", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+
+        assert_eq!(
+            res,
+            Ok(Some(
+                r#"{"user":{"name":"foo","id":1},"order":{"total":10.50},"tags":["a","b"]}"#
+            ))
+        );
+
+        let mut arglist = mock_args![
+            ("flat", "$opts", false),
+            ("foo", "user.name", false),
+            ("a", "tags[0]", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+
+        assert_eq!(res, Ok(Some(r#"{"user.name":"foo","tags[0]":"a"}"#)));
+    }
+
+    #[test]
+    fn test_array() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![];
+        let mut jsonify = JsonifyArray::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some("[]")));
+
+        let mut arglist = mock_args![
+            (1, "id", false),
+            ("foo", "name", false),
+            (Int None, "empty", true),
+            (Decimal "1.50", "cost", false),
+            (2, "id", false),
+        ];
+        let mut jsonify = JsonifyArray::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"[1,"foo",null,1.50,2]"#)));
+    }
+
+    #[test]
+    fn test_raw_json() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            (1, "id", false),
+            (r#"{"a":[1,2]}"#, "json:meta", false),
+            ("not json", "json:user.bad", false),
+            (String None, "json:empty", true),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+
+        assert_eq!(
+            res,
+            Ok(Some(
+                r#"{"id":1,"meta":{"a":[1,2]},"user":{"bad":"not json"},"empty":null}"#
+            ))
+        );
+
+        let mut arglist = mock_args![
+            ("invalid_json=error", "$opts", false),
+            ("not json", "json:bad", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        assert!(*cfg.maybe_null());
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        // Later rows are unaffected
+        let mut arglist = mock_args![
+            ("invalid_json=error", "$opts", false),
+            ("[1]", "json:bad", false),
+        ];
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"bad":[1]}"#)));
+    }
+
+    #[test]
+    fn test_decimals() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            (Decimal "1234567890123456789012345678.0123456789", "big", false),
+            (Decimal "-0.10", "small", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(
+                r#"{"big":1234567890123456789012345678.0123456789,"small":-0.10}"#
+            ))
+        );
+
+        let mut arglist = mock_args![
+            ("decimals=string", "$opts", false),
+            (Decimal "1234567890123456789012345678.0123456789", "big", false),
+            (Decimal "-0.10", "small", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(
+                r#"{"big":"1234567890123456789012345678.0123456789","small":"-0.10"}"#
+            ))
+        );
+
+        let mut jsonify = JsonifyArray::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(
+                r#"["1234567890123456789012345678.0123456789","-0.10"]"#
+            ))
+        );
+    }
+
+    #[test]
+    fn test_binary() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            ("text", "text", false),
+            (&b"\x00\xffab"[..], "bin", false),
+            ("ab", "hex:forced", false),
+            ("ab", "extjson:ext", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(concat!(
+                r#"{"text":"text","bin":"AP9hYg==","forced":"6162","#,
+                r#""ext":{"$binary":{"base64":"YWI=","subType":"00"}}}"#
+            )))
+        );
+
+        let mut arglist = mock_args![
+            ("binary=hex", "$opts", false),
+            ("text", "text", false),
+            (&b"\x00\xffab"[..], "bin", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"text":"text","bin":"00ff6162"}"#)));
+    }
+
+    #[test]
+    fn test_type_hints() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            (1, "active:bool", false),
+            (0, "deleted:bool", false),
+            ("maybe", "unsure:bool", false),
+            (Int None, "unknown:bool", true),
+            ("2023-01-02 03:04:05", "created:ts", false),
+            ("a, b,c", "tags:split", false),
+            ("", "empty:split", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(concat!(
+                r#"{"active":true,"deleted":false,"unsure":"maybe","unknown":null,"#,
+                r#""created":"2023-01-02T03:04:05Z","tags":["a","b","c"],"empty":[]}"#
+            )))
+        );
+    }
+
+    #[test]
+    fn test_sparse() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            (1, "id", false),
+            (String None, "name", true),
+            (Int None, "user.id", true),
+            (Int None, "tags[0]", true),
+            ("a", "tags[1]", false),
+        ];
+
+        let mut jsonify = JsonifySparse::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"id":1,"user":{},"tags":[null,"a"]}"#)));
+
+        let mut arglist = mock_args![
+            ("nulls=omit", "$opts", false),
+            (1, "id", false),
+            (String None, "name", true),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"id":1}"#)));
+
+        let mut arglist = mock_args![
+            ("nulls=keep", "$opts", false),
+            (1, "id", false),
+            (String None, "name", true),
+        ];
+
+        let mut jsonify = JsonifySparse::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"id":1,"name":null}"#)));
+    }
+
+    #[test]
+    fn test_nonfinite() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            (f64::NAN, "nan", false),
+            (f64::INFINITY, "inf", false),
+            (f64::NEG_INFINITY, "neg_inf", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"nan":null,"inf":null,"neg_inf":null}"#)));
+
+        let mut arglist = mock_args![
+            ("nonfinite=string", "$opts", false),
+            (f64::NAN, "nan", false),
+            (f64::INFINITY, "inf", false),
+            (f64::NEG_INFINITY, "neg_inf", false),
+        ];
+
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(
+                r#"{"nan":"NaN","inf":"Infinity","neg_inf":"-Infinity"}"#
+            ))
+        );
+
+        let mut arglist = mock_args![
+            ("nonfinite=error", "$opts", false),
+            (f64::NAN, "nan", false)
+        ];
+
+        let mut cfg = MockUdfCfg::new();
+        let mut jsonify = Jsonify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        assert!(*cfg.maybe_null());
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut array = JsonifyArray::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = array.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        // Later rows are unaffected
+        let mut arglist = mock_args![("nonfinite=error", "$opts", false), (1.5, "x", false)];
+        let res = array.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some("[1.5]")));
+    }
+
+    #[test]
+    fn test_pretty() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![(1, "id", false), ("é", "name", false)];
+
+        let mut jsonify = JsonifyPretty::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some("{\n  \"id\": 1,\n  \"name\": \"é\"\n}")));
+
+        let mut arglist = mock_args![
+            ("indent=0 ascii", "$opts", false),
+            (1, "id", false),
+            ("é", "name", false)
+        ];
+
+        let mut jsonify = JsonifyPretty::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = jsonify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(Some("{\n\"id\": 1,\n\"name\": \"\\u00e9\"\n}")));
     }
 }
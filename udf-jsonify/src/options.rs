@@ -0,0 +1,322 @@
+//! Options for the `jsonify` family of functions
+//!
+//! Options are passed as a constant string in a leading argument that is
+//! aliased as `$opts`. Entries are separated by commas or whitespace and are
+//! either flags or `key=value` pairs:
+//!
+//! ```sql
+//! SELECT jsonify('sorted, duplicates=first' AS "$opts", a, b) FROM t1;
+//! ```
+//!
+//! Available options:
+//!
+//! - `sorted`: emit object keys in alphabetical order rather than in argument
+//!   order
+//! - `flat`: use keys as-is rather than treating `a.b` and `a[0]` as paths into
+//!   nested objects and arrays
+//! - `duplicates=error|first|last`: what to do if two arguments have the same
+//!   key. `last` (the default) keeps the last argument with that key, `first`
+//!   keeps the first one, and `error` fails during `init`.
+//! - `invalid_json=string|error`: what to do if an argument marked with `json:`
+//!   does not contain valid JSON. `string` (the default) embeds it as a string,
+//!   `error` makes the result `NULL`.
+//! - `binary=base64|hex|extjson|lossy`: how to encode strings that are not
+//!   valid UTF-8, such as `BLOB`s or binary UUIDs. `base64` is the default,
+//!   `extjson` creates a `{"$binary": ...}` object, and `lossy` replaces invalid
+//!   bytes with `U+FFFD`. Valid UTF-8 is never changed.
+//! - `nulls=keep|omit`: whether object keys with `NULL` values are emitted as
+//!   `null` (the default) or left out entirely. Array elements are always kept
+//!   so that positions don't change.
+//! - `nonfinite=null|string|error`: how to emit NaN and infinite floats, which
+//!   JSON can't represent. `null` is the default, `string` emits `"NaN"`,
+//!   `"Infinity"` or `"-Infinity"`, and `error` makes the result `NULL`.
+//! - `indent=N`: pretty print the output, indenting with N spaces (max 16).
+//!   `jsonify_pretty` uses this with an indent of 2 by default.
+//! - `ascii`: escape all non-ASCII characters as `\uXXXX`, so the output is
+//!   safe to send through channels that aren't UTF-8 aware
+//! - `decimals=number|string`: emit `DECIMAL` values as numbers (the default)
+//!   or as strings, for consumers that can't handle large or precise numbers
+
+use udf::prelude::*;
+
+/// Alias that marks an argument as the options string
+pub const OPTIONS_ALIAS: &str = "$opts";
+
+/// Largest allowed `indent`
+const MAX_INDENT: usize = 16;
+
+/// How to handle two arguments that map to the same key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Refuse to run the function
+    Error,
+    /// Keep the value from the first argument
+    First,
+    /// Keep the value from the last argument
+    #[default]
+    Last,
+}
+
+/// How to handle arguments marked as JSON that can't be parsed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidJson {
+    /// Use the text as a string
+    #[default]
+    String,
+    /// Return `NULL`
+    Error,
+}
+
+/// How to emit `DECIMAL` values
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Decimals {
+    /// As a number with exactly the server's digits
+    #[default]
+    Number,
+    /// As a string
+    String,
+}
+
+/// How to encode binary data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Binary {
+    /// Replace invalid UTF-8 with the replacement character
+    Lossy,
+    /// Standard base64 with padding
+    #[default]
+    Base64,
+    /// Lowercase hex
+    Hex,
+    /// A MongoDB extended JSON `$binary` object
+    ExtJson,
+}
+
+/// How to handle `NULL` values in objects
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Nulls {
+    /// Emit `null`
+    #[default]
+    Keep,
+    /// Leave the key out
+    Omit,
+}
+
+/// How to handle NaN and infinite floats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonFinite {
+    /// Emit `null`
+    #[default]
+    Null,
+    /// Emit the JavaScript names as strings
+    String,
+    /// Return `NULL`
+    Error,
+}
+
+/// Parsed options
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    pub sorted: bool,
+    pub flat: bool,
+    pub duplicates: Duplicates,
+    pub invalid_json: InvalidJson,
+    pub decimals: Decimals,
+    pub binary: Binary,
+    pub nulls: Nulls,
+    pub nonfinite: NonFinite,
+    /// Pretty print with this many spaces if set
+    pub indent: Option<usize>,
+    pub ascii: bool,
+}
+
+impl Options {
+    /// Whether these options can make a row's result `NULL`
+    pub fn may_fail(&self) -> bool {
+        self.invalid_json == InvalidJson::Error || self.nonfinite == NonFinite::Error
+    }
+
+    /// Read options from the first argument if it is aliased as `$opts`,
+    /// applying them on top of `base`
+    ///
+    /// Returns the options and the number of arguments they consumed (0 or 1),
+    /// so callers know where their data arguments start.
+    pub fn from_args(args: &ArgList<Init>, mut base: Self) -> Result<(Self, usize), String> {
+        let Some(first) = args.get(0) else {
+            return Ok((base, 0));
+        };
+
+        if first.attribute() != OPTIONS_ALIAS {
+            return Ok((base, 0));
+        }
+
+        let value = first.value();
+        let Some(opt_str) = value.as_string() else {
+            return Err(format!(
+                "argument `{OPTIONS_ALIAS}` must be a constant string"
+            ));
+        };
+
+        base.update(opt_str)?;
+        Ok((base, 1))
+    }
+
+    /// Parse an options string
+    #[cfg(test)]
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut ret = Self::default();
+        ret.update(s)?;
+        Ok(ret)
+    }
+
+    /// Apply an options string on top of the current options
+    fn update(&mut self, s: &str) -> Result<(), String> {
+        for item in s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|item| !item.is_empty())
+        {
+            let (key, value) = match item.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (item, None),
+            };
+
+            match (key, value) {
+                ("sorted", None) => self.sorted = true,
+                ("flat", None) => self.flat = true,
+                ("ascii", None) => self.ascii = true,
+                ("indent", Some(v)) => match v.parse() {
+                    Ok(n) if n <= MAX_INDENT => self.indent = Some(n),
+                    _ => {
+                        return Err(format!(
+                            "invalid value '{v}' for option 'indent'; \
+                            expected a number from 0 to {MAX_INDENT}"
+                        ))
+                    }
+                },
+                ("duplicates", Some(v)) => {
+                    self.duplicates = match v {
+                        "error" => Duplicates::Error,
+                        "first" => Duplicates::First,
+                        "last" => Duplicates::Last,
+                        _ => {
+                            return Err(format!(
+                                "invalid value '{v}' for option 'duplicates'; \
+                                expected 'error', 'first' or 'last'"
+                            ))
+                        }
+                    }
+                }
+                ("invalid_json", Some(v)) => {
+                    self.invalid_json = match v {
+                        "string" => InvalidJson::String,
+                        "error" => InvalidJson::Error,
+                        _ => {
+                            return Err(format!(
+                                "invalid value '{v}' for option 'invalid_json'; \
+                                expected 'string' or 'error'"
+                            ))
+                        }
+                    }
+                }
+                ("decimals", Some(v)) => {
+                    self.decimals = match v {
+                        "number" => Decimals::Number,
+                        "string" => Decimals::String,
+                        _ => {
+                            return Err(format!(
+                                "invalid value '{v}' for option 'decimals'; \
+                                expected 'number' or 'string'"
+                            ))
+                        }
+                    }
+                }
+                ("binary", Some(v)) => {
+                    self.binary = match v {
+                        "lossy" => Binary::Lossy,
+                        "base64" => Binary::Base64,
+                        "hex" => Binary::Hex,
+                        "extjson" => Binary::ExtJson,
+                        _ => {
+                            return Err(format!(
+                                "invalid value '{v}' for option 'binary'; \
+                                expected 'base64', 'hex', 'extjson' or 'lossy'"
+                            ))
+                        }
+                    }
+                }
+                ("nulls", Some(v)) => {
+                    self.nulls = match v {
+                        "keep" => Nulls::Keep,
+                        "omit" => Nulls::Omit,
+                        _ => {
+                            return Err(format!(
+                                "invalid value '{v}' for option 'nulls'; \
+                                expected 'keep' or 'omit'"
+                            ))
+                        }
+                    }
+                }
+                ("nonfinite", Some(v)) => {
+                    self.nonfinite = match v {
+                        "null" => NonFinite::Null,
+                        "string" => NonFinite::String,
+                        "error" => NonFinite::Error,
+                        _ => {
+                            return Err(format!(
+                                "invalid value '{v}' for option 'nonfinite'; \
+                                expected 'null', 'string' or 'error'"
+                            ))
+                        }
+                    }
+                }
+                _ => return Err(format!("unrecognized option '{item}'")),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Options::parse(""), Ok(Options::default()));
+        assert_eq!(
+            Options::parse("sorted, duplicates=last decimals=string nulls=omit indent=4"),
+            Ok(Options {
+                indent: Some(4),
+                nulls: Nulls::Omit,
+                sorted: true,
+                duplicates: Duplicates::Last,
+                decimals: Decimals::String,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            Options::parse(
+                "duplicates=first flat invalid_json=error binary=hex nonfinite=string ascii"
+            ),
+            Ok(Options {
+                ascii: true,
+                nonfinite: NonFinite::String,
+                binary: Binary::Hex,
+                flat: true,
+                duplicates: Duplicates::First,
+                invalid_json: InvalidJson::Error,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Options::parse("sorted=yes").is_err());
+        assert!(Options::parse("duplicates=maybe").is_err());
+        assert!(Options::parse("unknown").is_err());
+        assert!(Options::parse("nulls=drop").is_err());
+        assert!(Options::parse("indent=17").is_err());
+        assert!(Options::parse("indent=-1").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Turning values into JSON text
+
+use std::fmt::Write;
+
+use serde::Serialize;
+use serde_json::ser::{PrettyFormatter, Serializer};
+use serde_json::Value;
+
+use crate::options::Options;
+
+/// Serialize a value as configured by `opts`
+pub fn to_json_string(value: &Value, opts: &Options) -> String {
+    let ret = match opts.indent {
+        None => value.to_string(),
+        Some(indent) => {
+            let indent_str = vec![b' '; indent];
+            let mut buf = Vec::new();
+            let mut ser =
+                Serializer::with_formatter(&mut buf, PrettyFormatter::with_indent(&indent_str));
+            value
+                .serialize(&mut ser)
+                .expect("serializing to a buffer cannot fail");
+            String::from_utf8(buf).expect("serde_json always produces valid UTF-8")
+        }
+    };
+
+    if opts.ascii {
+        escape_non_ascii(&ret)
+    } else {
+        ret
+    }
+}
+
+/// Replace all non-ASCII characters with `\uXXXX` escapes
+///
+/// This is only valid on serialized JSON, where non-ASCII characters can only
+/// appear within strings.
+fn escape_non_ascii(json: &str) -> String {
+    if json.is_ascii() {
+        return json.to_owned();
+    }
+
+    let mut ret = String::with_capacity(json.len() + json.len() / 2);
+    let mut utf16_buf = [0u16; 2];
+    for ch in json.chars() {
+        if ch.is_ascii() {
+            ret.push(ch);
+        } else {
+            for unit in ch.encode_utf16(&mut utf16_buf) {
+                write!(ret, "\\u{unit:04x}").unwrap();
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_pretty() {
+        let val = json!({"a": [1, 2], "b": {}});
+        let opts = Options {
+            indent: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            to_json_string(&val, &opts),
+            "{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": {}\n}"
+        );
+
+        let opts = Options {
+            indent: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            to_json_string(&json!({"a": 1}), &opts),
+            "{\n    \"a\": 1\n}"
+        );
+    }
+
+    #[test]
+    fn test_ascii() {
+        let val = json!({"naïve": "日本 🎉"});
+        let opts = Options {
+            ascii: true,
+            ..Default::default()
+        };
+        let res = to_json_string(&val, &opts);
+        assert_eq!(res, r#"{"na\u00efve":"\u65e5\u672c \ud83c\udf89"}"#);
+        assert_eq!(serde_json::from_str::<Value>(&res).unwrap(), val);
+    }
+}
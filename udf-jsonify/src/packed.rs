@@ -0,0 +1,523 @@
+//! Binary encodings of rows: [MessagePack] and [CBOR]
+//!
+//! `msgpackify` and `cborify` take the same arguments and options as
+//! `jsonify` and build the same document, but return it as a binary map.
+//! Integers are written as integers and all other numbers (including
+//! `DECIMAL`s) as floats: always 64-bit for MessagePack, and the smallest size
+//! that holds the value exactly for CBOR. Strings that are not valid UTF-8 and
+//! arguments with a binary prefix such as `base64:` are written as native
+//! binary strings rather than being encoded as text.
+//!
+//! `msgpack_to_json` and `cbor_to_json` decode a blob back to JSON for
+//! inspection. Since JSON has fewer types, binary strings and MessagePack
+//! extension data become base64 strings, non-finite floats become `null`, map
+//! keys that are not strings are converted to their JSON text, and CBOR tags
+//! are dropped in favor of the tagged value. Blobs that cannot be decoded, or
+//! that have trailing bytes, return `NULL` with a warning.
+//!
+//! [MessagePack]: https://msgpack.org/
+//! [CBOR]: https://www.rfc-editor.org/rfc/rfc8949
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{Map, Number, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+use crate::convert::ArgKind;
+use crate::options::Options;
+use crate::template::Document;
+use crate::ObjectSpec;
+
+/// Shared state for the binary encoders
+#[derive(Debug, Default)]
+struct Packed {
+    spec: ObjectSpec,
+    res: Vec<u8>,
+}
+
+impl Packed {
+    fn new(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let spec = ObjectSpec::from_args(args, Options::default())?;
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        cfg.set_maybe_null(spec.opts.may_fail());
+        Ok(Self {
+            spec,
+            res: Vec::new(),
+        })
+    }
+
+    /// Fill the template with the values of the current row
+    ///
+    /// Binary strings are passed to `bin` as-is, and all other values are
+    /// converted to JSON first and then passed to `from_json`.
+    fn build<T: Document>(
+        &self,
+        args: &ArgList<Process>,
+        from_json: fn(Value) -> T,
+        bin: fn(Vec<u8>) -> T,
+    ) -> Result<T, ProcessError> {
+        let spec = &self.spec;
+        spec.template.build(|idx| {
+            let arg = args.get(idx).ok_or(ProcessError)?;
+            if let SqlResult::String(Some(bytes)) = arg.value() {
+                let is_binary = match spec.kinds[idx] {
+                    ArgKind::Binary(_) => true,
+                    ArgKind::Json => false,
+                    _ => std::str::from_utf8(bytes).is_err(),
+                };
+                if is_binary {
+                    return Ok(Some(bin(bytes.to_owned())));
+                }
+            }
+            Ok(spec.convert(args, idx)?.map(from_json))
+        })
+    }
+}
+
+impl Document for rmpv::Value {
+    fn null() -> Self {
+        rmpv::Value::Nil
+    }
+
+    fn object(entries: Vec<(String, Self)>) -> Self {
+        rmpv::Value::Map(entries.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    fn array(items: Vec<Self>) -> Self {
+        rmpv::Value::Array(items)
+    }
+}
+
+impl Document for ciborium::Value {
+    fn null() -> Self {
+        ciborium::Value::Null
+    }
+
+    fn object(entries: Vec<(String, Self)>) -> Self {
+        ciborium::Value::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (ciborium::Value::Text(k), v))
+                .collect(),
+        )
+    }
+
+    fn array(items: Vec<Self>) -> Self {
+        ciborium::Value::Array(items)
+    }
+}
+
+/// `jsonify` that creates MessagePack
+#[derive(Debug, Default)]
+struct Msgpackify(Packed);
+
+#[register]
+impl BasicUdf for Msgpackify {
+    type Returns<'a> = Option<&'a [u8]>;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        Packed::new(cfg, args).map(Self)
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        // Conversion errors have already been logged
+        let Ok(value) = self.0.build(args, json_to_msgpack, rmpv::Value::Binary) else {
+            return Ok(None);
+        };
+        self.0.res.clear();
+        // Writing to a `Vec` cannot fail
+        rmpv::encode::write_value(&mut self.0.res, &value).unwrap();
+        Ok(Some(&self.0.res))
+    }
+}
+
+/// `jsonify` that creates CBOR
+#[derive(Debug, Default)]
+struct Cborify(Packed);
+
+#[register]
+impl BasicUdf for Cborify {
+    type Returns<'a> = Option<&'a [u8]>;
+
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        Packed::new(cfg, args).map(Self)
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let Ok(value) = self.0.build(args, json_to_cbor, ciborium::Value::Bytes) else {
+            return Ok(None);
+        };
+        self.0.res.clear();
+        ciborium::into_writer(&value, &mut self.0.res).unwrap();
+        Ok(Some(&self.0.res))
+    }
+}
+
+/// Check for a single blob argument
+fn init_decoder(cfg: &UdfCfg<Init>, args: &ArgList<Init>, name: &str) -> Result<(), String> {
+    if args.len() != 1 {
+        return Err(format!(
+            "{name} takes 1 argument but got {} (usage: `{name}(blob)`)",
+            args.len()
+        ));
+    }
+    args.get(0).unwrap().set_type_coercion(SqlType::String);
+    cfg.set_max_len(MaxLenOptions::Blob as u64);
+    cfg.set_maybe_null(true);
+    Ok(())
+}
+
+/// Decode MessagePack to JSON
+#[derive(Debug, Default)]
+struct MsgpackToJson(String);
+
+#[register]
+impl BasicUdf for MsgpackToJson {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `msgpack_to_json(blob)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_decoder(cfg, args, "msgpack_to_json")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let arg = args.get(0).unwrap().value();
+        let Some(bytes) = arg.as_bytes() else {
+            return Ok(None);
+        };
+        match decode_msgpack(bytes) {
+            Ok(value) => {
+                self.0 = value.to_string();
+                Ok(Some(&self.0))
+            }
+            Err(e) => {
+                udf_log!(Warning: "msgpack_to_json: {e}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Decode CBOR to JSON
+#[derive(Debug, Default)]
+struct CborToJson(String);
+
+#[register]
+impl BasicUdf for CborToJson {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `cbor_to_json(blob)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_decoder(cfg, args, "cbor_to_json")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let arg = args.get(0).unwrap().value();
+        let Some(bytes) = arg.as_bytes() else {
+            return Ok(None);
+        };
+        match decode_cbor(bytes) {
+            Ok(value) => {
+                self.0 = value.to_string();
+                Ok(Some(&self.0))
+            }
+            Err(e) => {
+                udf_log!(Warning: "cbor_to_json: {e}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Convert a JSON value to MessagePack
+fn json_to_msgpack(value: Value) -> rmpv::Value {
+    match value {
+        Value::Null => rmpv::Value::Nil,
+        Value::Bool(v) => rmpv::Value::Boolean(v),
+        Value::Number(n) => {
+            if let Some(v) = n.as_i64() {
+                v.into()
+            } else if let Some(v) = n.as_u64() {
+                v.into()
+            } else {
+                n.as_f64().unwrap_or(f64::NAN).into()
+            }
+        }
+        Value::String(s) => s.into(),
+        Value::Array(arr) => rmpv::Value::Array(arr.into_iter().map(json_to_msgpack).collect()),
+        Value::Object(map) => rmpv::Value::Map(
+            map.into_iter()
+                .map(|(k, v)| (k.into(), json_to_msgpack(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Convert a JSON value to CBOR
+fn json_to_cbor(value: Value) -> ciborium::Value {
+    match value {
+        Value::Null => ciborium::Value::Null,
+        Value::Bool(v) => ciborium::Value::Bool(v),
+        Value::Number(n) => {
+            if let Some(v) = n.as_i64() {
+                ciborium::Value::Integer(v.into())
+            } else if let Some(v) = n.as_u64() {
+                ciborium::Value::Integer(v.into())
+            } else {
+                ciborium::Value::Float(n.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        Value::String(s) => ciborium::Value::Text(s),
+        Value::Array(arr) => ciborium::Value::Array(arr.into_iter().map(json_to_cbor).collect()),
+        Value::Object(map) => ciborium::Value::Map(
+            map.into_iter()
+                .map(|(k, v)| (ciborium::Value::Text(k), json_to_cbor(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// A float as JSON, or `null` if it is not finite
+fn float_value(v: f64) -> Value {
+    Number::from_f64(v).map_or(Value::Null, Value::Number)
+}
+
+/// Turn a converted map key into an object key
+pub fn key_string(key: Value) -> String {
+    match key {
+        Value::String(s) => s,
+        key => key.to_string(),
+    }
+}
+
+/// Decode a complete MessagePack value
+pub fn decode_msgpack(mut bytes: &[u8]) -> Result<Value, String> {
+    let value = rmpv::decode::read_value(&mut bytes).map_err(|e| format!("invalid data: {e}"))?;
+    if !bytes.is_empty() {
+        return Err(format!("{} unexpected trailing bytes", bytes.len()));
+    }
+    Ok(from_msgpack(value))
+}
+
+/// Convert a MessagePack value to JSON
+fn from_msgpack(value: rmpv::Value) -> Value {
+    match value {
+        rmpv::Value::Nil => Value::Null,
+        rmpv::Value::Boolean(v) => Value::Bool(v),
+        rmpv::Value::Integer(i) => match (i.as_i64(), i.as_u64()) {
+            (Some(v), _) => v.into(),
+            (None, Some(v)) => v.into(),
+            (None, None) => Value::Null,
+        },
+        rmpv::Value::F32(v) => float_value(v.into()),
+        rmpv::Value::F64(v) => float_value(v),
+        rmpv::Value::String(s) => match s.as_str() {
+            Some(s) => Value::String(s.to_owned()),
+            None => Value::String(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        },
+        rmpv::Value::Binary(b) | rmpv::Value::Ext(_, b) => Value::String(BASE64.encode(b)),
+        rmpv::Value::Array(arr) => Value::Array(arr.into_iter().map(from_msgpack).collect()),
+        rmpv::Value::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| (key_string(from_msgpack(k)), from_msgpack(v)))
+                .collect::<Map<_, _>>(),
+        ),
+    }
+}
+
+/// Decode a complete CBOR value
+pub fn decode_cbor(mut bytes: &[u8]) -> Result<Value, String> {
+    let value: ciborium::Value =
+        ciborium::from_reader(&mut bytes).map_err(|e| format!("invalid data: {e}"))?;
+    if !bytes.is_empty() {
+        return Err(format!("{} unexpected trailing bytes", bytes.len()));
+    }
+    Ok(from_cbor(value))
+}
+
+/// Convert a CBOR value to JSON
+fn from_cbor(value: ciborium::Value) -> Value {
+    match value {
+        ciborium::Value::Null => Value::Null,
+        ciborium::Value::Bool(v) => Value::Bool(v),
+        // CBOR integers can be 65 bits, which `arbitrary_precision` handles
+        ciborium::Value::Integer(i) => i128::from(i)
+            .to_string()
+            .parse::<Number>()
+            .map_or(Value::Null, Value::Number),
+        ciborium::Value::Float(v) => float_value(v),
+        ciborium::Value::Text(s) => Value::String(s),
+        ciborium::Value::Bytes(b) => Value::String(BASE64.encode(b)),
+        ciborium::Value::Tag(_, inner) => from_cbor(*inner),
+        ciborium::Value::Array(arr) => Value::Array(arr.into_iter().map(from_cbor).collect()),
+        ciborium::Value::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| (key_string(from_cbor(k)), from_cbor(v)))
+                .collect::<Map<_, _>>(),
+        ),
+        // `ciborium::Value` is non-exhaustive
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_msgpackify() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![
+            (1, "id", false),
+            ("x", "user.name", false),
+            (String None, "note", true),
+        ];
+        let mut udf = Msgpackify::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(
+                &b"\x83\xa2id\x01\xa4user\x81\xa4name\xa1x\xa4note\xc0"[..]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_cborify() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(-2, "a", false), (1.5, "b", false), (String None, "c", true)];
+        let mut udf = Cborify::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(&b"\xa3\x61a\x21\x61b\xf9\x3e\x00\x61c\xf6"[..]))
+        );
+    }
+
+    #[test]
+    fn test_conversion_error() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![("nonfinite=error", "$opts", false), (f64::NAN, "x", false)];
+        let mut udf = Msgpackify::init(cfg.as_init(), args.as_init()).unwrap();
+        assert!(*cfg.maybe_null());
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut udf = Cborify::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+    }
+
+    #[test]
+    fn test_binary_args() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![
+            (&b"\xff\x00"[..], "raw", false),
+            ("ab", "base64:b", false),
+            ("ok", "s", false),
+        ];
+        let mut udf = Msgpackify::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(
+                &b"\x83\xa3raw\xc4\x02\xff\x00\xa1b\xc4\x02ab\xa1s\xa2ok"[..]
+            ))
+        );
+
+        let mut udf = Cborify::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(Some(&b"\xa3\x63raw\x42\xff\x00\x61b\x42ab\x61s\x62ok"[..]))
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let doc = json!({
+            "int": -5,
+            "big": 18446744073709551615u64,
+            "float": 2.5,
+            "s": "héllo",
+            "list": [true, null, {}],
+        });
+
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &json_to_msgpack(doc.clone())).unwrap();
+        assert_eq!(decode_msgpack(&buf).unwrap(), doc);
+
+        let mut buf = Vec::new();
+        ciborium::into_writer(&json_to_cbor(doc.clone()), &mut buf).unwrap();
+        assert_eq!(decode_cbor(&buf).unwrap(), doc);
+    }
+
+    #[test]
+    fn test_decode_json_lossy() {
+        // {1: bin "ab", "e": ext(5, "\x00"), "f": NaN}
+        let msgpack =
+            b"\x83\x01\xc4\x02ab\xa1e\xd4\x05\x00\xa1f\xcb\x7f\xf8\x00\x00\x00\x00\x00\x00";
+        assert_eq!(
+            decode_msgpack(msgpack).unwrap(),
+            json!({"1": "YWI=", "e": "AA==", "f": null})
+        );
+
+        // {"t": 1(1000), "n": -2^64}
+        let cbor = b"\xa2\x61t\xc1\x19\x03\xe8\x61n\x3b\xff\xff\xff\xff\xff\xff\xff\xff";
+        assert_eq!(
+            decode_cbor(cbor).unwrap().to_string(),
+            r#"{"t":1000,"n":-18446744073709551616}"#
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(decode_msgpack(b"\x92\x01").is_err());
+        assert!(decode_msgpack(b"\x01\x02").is_err());
+        assert!(decode_cbor(b"\x82\x01").is_err());
+        assert!(decode_cbor(b"\x01\x02").is_err());
+
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(&b"\x92\x01"[..], "blob", false)];
+        let mut udf = MsgpackToJson::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![(&b"\xa1\x61a\x01"[..], "blob", false)];
+        let mut udf = CborToJson::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"a":1}"#)));
+
+        let mut args = mock_args![(String None, "blob", true)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+    }
+}
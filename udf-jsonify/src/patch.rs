@@ -0,0 +1,547 @@
+//! JSON Merge Patch ([RFC 7386]) and JSON Patch ([RFC 6902])
+//!
+//! Both functions return `NULL` if either argument is `NULL`. Invalid JSON, a
+//! malformed patch, or a JSON Patch operation that fails (including a `test`
+//! that doesn't match) also return `NULL` and log a warning that says which
+//! operation failed and why. JSON Patch is applied atomically: if any operation
+//! fails, none of them are applied.
+//!
+//! [RFC 7386]: https://www.rfc-editor.org/rfc/rfc7386
+//! [RFC 6902]: https://www.rfc-editor.org/rfc/rfc6902
+
+use serde_json::{Map, Number, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+use crate::parse_json_arg;
+
+/// Check for two string-coercible arguments
+fn init_two_docs(cfg: &UdfCfg<Init>, args: &ArgList<Init>, usage: &str) -> Result<(), String> {
+    if args.len() != 2 {
+        return Err(format!("{usage} takes 2 arguments but got {}", args.len()));
+    }
+    for mut arg in args {
+        arg.set_type_coercion(SqlType::String);
+    }
+    cfg.set_max_len(MaxLenOptions::Blob as u64);
+    cfg.set_maybe_null(true);
+    Ok(())
+}
+
+/// Apply an RFC 7386 merge patch
+#[derive(Debug, Default)]
+struct JsonMergePatch(String);
+
+#[register]
+impl BasicUdf for JsonMergePatch {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_merge_patch(target, patch)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_two_docs(cfg, args, "json_merge_patch(target, patch)")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let name = "json_merge_patch";
+        let (Some(mut target), Some(patch)) = (
+            parse_json_arg(args.get(0).unwrap().value(), name),
+            parse_json_arg(args.get(1).unwrap().value(), name),
+        ) else {
+            return Ok(None);
+        };
+
+        merge_patch(&mut target, patch);
+        self.0 = target.to_string();
+        Ok(Some(&self.0))
+    }
+}
+
+/// Apply a list of RFC 6902 operations
+#[derive(Debug, Default)]
+struct JsonPatch(String);
+
+#[register]
+impl BasicUdf for JsonPatch {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_patch(doc, ops)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        init_two_docs(cfg, args, "json_patch(doc, ops)")?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let name = "json_patch";
+        let (Some(doc), Some(ops)) = (
+            parse_json_arg(args.get(0).unwrap().value(), name),
+            parse_json_arg(args.get(1).unwrap().value(), name),
+        ) else {
+            return Ok(None);
+        };
+
+        match apply_patch(doc, &ops) {
+            Ok(patched) => self.0 = patched.to_string(),
+            Err(e) => {
+                udf_log!(Warning: "json_patch: {e}");
+                return Ok(None);
+            }
+        }
+        Ok(Some(&self.0))
+    }
+}
+
+/// Merge `patch` into `target` as described in RFC 7386
+pub fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let map = target.as_object_mut().unwrap();
+
+    for (key, val) in patch {
+        if val.is_null() {
+            map.shift_remove(&key);
+        } else {
+            merge_patch(map.entry(key).or_insert(Value::Null), val);
+        }
+    }
+}
+
+/// Split a JSON pointer (RFC 6901) into unescaped reference tokens
+fn parse_pointer(ptr: &str) -> Result<Vec<String>, String> {
+    if ptr.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = ptr.strip_prefix('/') else {
+        return Err(format!(
+            "invalid JSON pointer '{ptr}'; must be empty or start with '/'"
+        ));
+    };
+
+    rest.split('/')
+        .map(|tok| {
+            let mut ret = String::with_capacity(tok.len());
+            let mut chars = tok.chars();
+            while let Some(ch) = chars.next() {
+                if ch != '~' {
+                    ret.push(ch);
+                    continue;
+                }
+                // Every `~` must start a `~0` or `~1` escape
+                match chars.next() {
+                    Some('0') => ret.push('~'),
+                    Some('1') => ret.push('/'),
+                    _ => return Err(format!("invalid escape in JSON pointer '{ptr}'")),
+                }
+            }
+            Ok(ret)
+        })
+        .collect()
+}
+
+/// Parse an array index token for an array of length `len`
+///
+/// If `allow_end` is set, the index may also be `len` or `-` (meaning `len`),
+/// which is where `add` appends.
+fn parse_index(tok: &str, len: usize, allow_end: bool) -> Result<usize, String> {
+    if allow_end && tok == "-" {
+        return Ok(len);
+    }
+    let valid = !tok.is_empty()
+        && tok.bytes().all(|b| b.is_ascii_digit())
+        && (tok == "0" || !tok.starts_with('0'));
+    let idx = valid
+        .then(|| tok.parse::<usize>().ok())
+        .flatten()
+        .ok_or_else(|| format!("'{tok}' is not a valid array index"))?;
+
+    let in_bounds = if allow_end { idx <= len } else { idx < len };
+    if !in_bounds {
+        return Err(format!("array index {idx} is out of bounds"));
+    }
+    Ok(idx)
+}
+
+/// Get the value at a parsed pointer
+fn get_mut<'v>(doc: &'v mut Value, path: &[String]) -> Result<&'v mut Value, String> {
+    path.iter().try_fold(doc, |cur, tok| match cur {
+        Value::Object(map) => map
+            .get_mut(tok)
+            .ok_or_else(|| format!("key '{tok}' does not exist")),
+        Value::Array(arr) => {
+            let idx = parse_index(tok, arr.len(), false)?;
+            Ok(&mut arr[idx])
+        }
+        _ => Err(format!("cannot index into a scalar with '{tok}'")),
+    })
+}
+
+/// Add `val` at `path`, inserting into arrays
+fn add(doc: &mut Value, path: &[String], val: Value) -> Result<(), String> {
+    let Some((last, parent)) = path.split_last() else {
+        *doc = val;
+        return Ok(());
+    };
+    match get_mut(doc, parent)? {
+        Value::Object(map) => {
+            map.insert(last.clone(), val);
+        }
+        Value::Array(arr) => {
+            let idx = parse_index(last, arr.len(), true)?;
+            arr.insert(idx, val);
+        }
+        _ => return Err("parent of the target location is not a container".to_owned()),
+    }
+    Ok(())
+}
+
+/// Remove and return the value at `path`
+fn remove(doc: &mut Value, path: &[String]) -> Result<Value, String> {
+    let Some((last, parent)) = path.split_last() else {
+        return Ok(std::mem::take(doc));
+    };
+    match get_mut(doc, parent)? {
+        Value::Object(map) => map
+            .shift_remove(last)
+            .ok_or_else(|| format!("key '{last}' does not exist")),
+        Value::Array(arr) => {
+            let idx = parse_index(last, arr.len(), false)?;
+            Ok(arr.remove(idx))
+        }
+        _ => Err("parent of the target location is not a container".to_owned()),
+    }
+}
+
+/// Split the text of a JSON number into its sign, significant digits and the
+/// exponent of the last digit, so that e.g. `1.50`, `15e-1` and `0.15E1` all
+/// give the same result
+///
+/// Returns `None` if the exponent doesn't fit into an `i64`.
+fn decimal_parts(num: &str) -> Option<(bool, String, i64)> {
+    let (neg, num) = match num.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, num),
+    };
+    let (mantissa, exp) = match num.split_once(['e', 'E']) {
+        Some((mantissa, exp)) => (mantissa, exp.parse::<i64>().ok()?),
+        None => (num, 0),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    // Move the fraction into the exponent so that only digits remain
+    let digits = format!("{int}{frac}");
+    let digits = digits.trim_start_matches('0');
+    let significant = digits.trim_end_matches('0');
+    if significant.is_empty() {
+        // All zeros are equal, including `-0`
+        return Some((false, String::new(), 0));
+    }
+    let shift =
+        i64::try_from(digits.len() - significant.len()).ok()? - i64::try_from(frac.len()).ok()?;
+    Some((neg, significant.to_owned(), exp.checked_add(shift)?))
+}
+
+/// Check whether two numbers have exactly the same value
+///
+/// Numbers are compared as decimals rather than as floats, so large integers
+/// and precise decimals are only equal if every digit matches.
+pub fn number_eq(x: &Number, y: &Number) -> bool {
+    match (decimal_parts(x.as_str()), decimal_parts(y.as_str())) {
+        (Some(x), Some(y)) => x == y,
+        // Exponents too large to normalize must match exactly
+        _ => x.as_str() == y.as_str(),
+    }
+}
+
+/// Compare values, treating numbers as equal if they have the same value
+pub fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => number_eq(x, y),
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(x, y)| json_eq(x, y))
+        }
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len()
+                && x.iter()
+                    .all(|(k, xv)| y.get(k).is_some_and(|yv| json_eq(xv, yv)))
+        }
+        _ => a == b,
+    }
+}
+
+/// Apply a single operation object
+fn apply_op(doc: &mut Value, op: &Value) -> Result<(), String> {
+    let Value::Object(op) = op else {
+        return Err("operation is not an object".to_owned());
+    };
+    let member = |name: &str| {
+        op.get(name)
+            .ok_or_else(|| format!("missing member '{name}'"))
+    };
+    let pointer = |name: &str| match member(name)? {
+        Value::String(s) => parse_pointer(s),
+        _ => Err(format!("member '{name}' is not a string")),
+    };
+
+    let path = pointer("path")?;
+    match member("op")?.as_str() {
+        Some("add") => add(doc, &path, member("value")?.clone()),
+        Some("remove") => remove(doc, &path).map(|_| ()),
+        Some("replace") => {
+            *get_mut(doc, &path)? = member("value")?.clone();
+            Ok(())
+        }
+        Some("move") => {
+            let from = pointer("from")?;
+            if path.len() > from.len() && path.starts_with(&from) {
+                return Err("cannot move a value into one of its children".to_owned());
+            }
+            let val = remove(doc, &from)?;
+            add(doc, &path, val)
+        }
+        Some("copy") => {
+            let val = get_mut(doc, &pointer("from")?)?.clone();
+            add(doc, &path, val)
+        }
+        Some("test") => {
+            let expected = member("value")?;
+            let actual = get_mut(doc, &path)?;
+            if json_eq(actual, expected) {
+                Ok(())
+            } else {
+                Err(format!("value {actual} does not equal {expected}"))
+            }
+        }
+        _ => Err(format!("unknown op {}", member("op")?)),
+    }
+}
+
+/// Apply a JSON Patch document, failing without changes if any operation fails
+pub fn apply_patch(mut doc: Value, ops: &Value) -> Result<Value, String> {
+    let Value::Array(ops) = ops else {
+        return Err("patch must be an array of operations".to_owned());
+    };
+
+    for (i, op) in ops.iter().enumerate() {
+        apply_op(&mut doc, op).map_err(|e| {
+            let name = op.get("op").and_then(Value::as_str).unwrap_or("?");
+            let path = op.get("path").and_then(Value::as_str).unwrap_or("?");
+            format!("operation {i} ('{name}' at '{path}') failed: {e}")
+        })?;
+    }
+
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_merge_patch() {
+        // Test cases from RFC 7386 appendix A
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+
+        for (mut target, patch, expected) in cases {
+            merge_patch(&mut target, patch);
+            assert_eq!(target, expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_pointer() {
+        assert_eq!(parse_pointer(""), Ok(vec![]));
+        assert_eq!(parse_pointer("/"), Ok(vec![String::new()]));
+        assert_eq!(
+            parse_pointer("/a~1b/m~0n/0"),
+            Ok(vec!["a/b".to_owned(), "m~n".to_owned(), "0".to_owned()])
+        );
+        assert_eq!(parse_pointer("/~01"), Ok(vec!["~1".to_owned()]));
+        assert!(parse_pointer("a").is_err());
+        assert!(parse_pointer("/a~2").is_err());
+        assert!(parse_pointer("/~~01").is_err());
+        assert!(parse_pointer("/~~10").is_err());
+        assert!(parse_pointer("/a~").is_err());
+    }
+
+    #[test]
+    fn test_patch() {
+        let doc = json!({"foo": "bar", "arr": [1, 2], "obj": {"x": 1}});
+        let ops = json!([
+            {"op": "add", "path": "/baz", "value": "qux"},
+            {"op": "add", "path": "/arr/1", "value": 9},
+            {"op": "add", "path": "/arr/-", "value": 3},
+            {"op": "remove", "path": "/foo"},
+            {"op": "replace", "path": "/obj/x", "value": [true]},
+            {"op": "copy", "from": "/arr/0", "path": "/first"},
+            {"op": "move", "from": "/baz", "path": "/obj/baz"},
+            {"op": "test", "path": "/arr", "value": [1.0, 9, 2, 3]},
+        ]);
+        assert_eq!(
+            apply_patch(doc, &ops).unwrap(),
+            json!({"arr": [1, 9, 2, 3], "obj": {"x": [true], "baz": "qux"}, "first": 1})
+        );
+
+        assert_eq!(
+            apply_patch(json!({}), &json!([{"op": "add", "path": "", "value": [1]}])).unwrap(),
+            json!([1])
+        );
+    }
+
+    #[test]
+    fn test_json_eq() {
+        let eq = |a: &str, b: &str| {
+            let a: Value = serde_json::from_str(a).unwrap();
+            let b: Value = serde_json::from_str(b).unwrap();
+            json_eq(&a, &b)
+        };
+        assert!(eq("1", "1.0"));
+        assert!(eq("150", "1.5e2"));
+        assert!(eq("0.015", "15E-3"));
+        assert!(eq("-0", "0.00"));
+        assert!(eq("[1, {\"a\": 2.50}]", "[1.0, {\"a\": 2.5}]"));
+        assert!(eq("1e400", "10e399"));
+
+        assert!(!eq("1234567890123456789", "1234567890123456788"));
+        assert!(!eq("9007199254740993", "9007199254740992"));
+        assert!(!eq("10.000000000000000001", "10"));
+        assert!(!eq("1e400", "2e400"));
+        assert!(!eq("-1", "1"));
+        assert!(!eq("1", "\"1\""));
+    }
+
+    #[test]
+    fn test_patch_errors() {
+        let doc = json!({"a": {"b": 1}, "arr": [0]});
+        let fail = |ops: Value| apply_patch(doc.clone(), &ops).unwrap_err();
+
+        assert_eq!(
+            fail(json!([{"op": "test", "path": "/a/b", "value": 2}])),
+            "operation 0 ('test' at '/a/b') failed: value 1 does not equal 2"
+        );
+        assert_eq!(
+            fail(json!([
+                {"op": "add", "path": "/c", "value": 1},
+                {"op": "remove", "path": "/missing"}
+            ])),
+            "operation 1 ('remove' at '/missing') failed: key 'missing' does not exist"
+        );
+        assert!(fail(json!([{"op": "add", "path": "/x/y", "value": 1}])).contains("'x'"));
+        assert!(fail(json!([{"op": "add", "path": "/arr/2", "value": 1}])).contains("bounds"));
+        assert!(fail(json!([{"op": "replace", "path": "/arr/01", "value": 1}])).contains("index"));
+        assert!(fail(json!([{"op": "move", "from": "/a", "path": "/a/b/c"}])).contains("children"));
+        assert!(fail(json!([{"op": "frob", "path": "/a"}])).contains("unknown op"));
+        assert!(fail(json!([{"op": "add", "path": "/a"}])).contains("'value'"));
+        assert!(fail(json!({"op": "add"})).contains("array"));
+
+        let doc: Value = serde_json::from_str(r#"{"id": 9007199254740993}"#).unwrap();
+        let ops: Value =
+            serde_json::from_str(r#"[{"op": "test", "path": "/id", "value": 9007199254740992}]"#)
+                .unwrap();
+        assert!(apply_patch(doc, &ops).is_err());
+    }
+
+    #[test]
+    fn test_udfs() {
+        let mut cfg = MockUdfCfg::new();
+
+        let mut args = mock_args![
+            (r#"{"a": 1, "b": {"c": 2}}"#, "t", false),
+            (r#"{"a": null, "b": {"d": 3}}"#, "p", false)
+        ];
+        let mut merge = JsonMergePatch::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = merge.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"b":{"c":2,"d":3}}"#)));
+
+        let mut args = mock_args![(String None, "t", true), ("{}", "p", false)];
+        let res = merge.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![
+            (r#"{"v": 1}"#, "d", false),
+            (
+                r#"[{"op": "test", "path": "/v", "value": 1}, {"op": "replace", "path": "/v", "value": 2}]"#,
+                "o",
+                false
+            )
+        ];
+        let mut patch = JsonPatch::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = patch.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"v":2}"#)));
+
+        let mut args = mock_args![
+            (r#"{"v": 1}"#, "d", false),
+            (r#"[{"op": "test", "path": "/v", "value": 3}]"#, "o", false)
+        ];
+        let res = patch.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![("{not json", "d", false), ("[]", "o", false)];
+        let res = patch.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![("{}", "d", false)];
+        assert!(JsonPatch::init(cfg.as_init(), args.as_init()).is_err());
+    }
+}
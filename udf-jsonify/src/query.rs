@@ -0,0 +1,286 @@
+//! Querying documents with [JMESPath]
+//!
+//! `json_query` returns its result as JSON text, and `json_query_scalar`
+//! unwraps a string, number or boolean result to plain text (e.g. `abc` rather
+//! than `"abc"`), returning `NULL` for anything else. Both return `NULL` if the
+//! document is `NULL` or if nothing matches. A document that is not valid JSON
+//! or an expression that fails (e.g. a function given the wrong type) returns
+//! `NULL` with a warning.
+//!
+//! If the expression is a constant it is compiled once in `init`, and an
+//! invalid expression is reported there. Otherwise it is compiled for every
+//! row, and an invalid expression returns `NULL` with a warning.
+//!
+//! JMESPath objects don't keep key order, so objects in the result have their
+//! keys sorted.
+//!
+//! [JMESPath]: https://jmespath.org/
+
+use jmespath::{Context, Expression, Rcvar, Variable, DEFAULT_RUNTIME};
+use serde_json::{Map, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+use crate::parse_json_arg;
+
+/// Query a document with a JMESPath expression
+#[derive(Debug, Default)]
+struct JsonQuery {
+    /// Compiled expression, if it was constant
+    expr: Option<Expression<'static>>,
+    res: String,
+}
+
+impl JsonQuery {
+    fn new(cfg: &UdfCfg<Init>, args: &ArgList<Init>, name: &str) -> Result<Self, String> {
+        if args.len() != 2 {
+            return Err(format!(
+                "{name} takes 2 arguments but got {} (usage: `{name}(doc, expression)`)",
+                args.len()
+            ));
+        }
+        // The value is only available during `init` if it is constant
+        let expr = match args.get(1).unwrap().value().as_string() {
+            Some(s) => {
+                Some(jmespath::compile(s).map_err(|e| format!("{name}: invalid expression: {e}"))?)
+            }
+            None => None,
+        };
+
+        for mut arg in args {
+            arg.set_type_coercion(SqlType::String);
+        }
+
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        cfg.set_maybe_null(true);
+        Ok(Self {
+            expr,
+            res: String::new(),
+        })
+    }
+
+    /// Run the query, returning `None` if the document or expression is `NULL`
+    ///
+    /// Errors are logged and also return `None`.
+    fn search(&self, args: &ArgList<Process>, name: &str) -> Option<Value> {
+        let doc = parse_json_arg(args.get(0).unwrap().value(), name)?;
+
+        let compiled;
+        let expr = match &self.expr {
+            Some(expr) => expr,
+            None => {
+                let value = args.get(1).unwrap().value();
+                compiled = jmespath::compile(value.as_string()?)
+                    .map_err(|e| udf_log!(Warning: "{name}: invalid expression: {e}"))
+                    .ok()?;
+                &compiled
+            }
+        };
+
+        // `Expression::search` would convert the document with serde, so run
+        // the interpreter directly with our own conversion
+        let data = Rcvar::new(to_variable(doc));
+        let mut ctx = Context::new(expr.as_str(), &DEFAULT_RUNTIME);
+        let found = jmespath::interpret(&data, expr.as_ast(), &mut ctx)
+            .map_err(|e| udf_log!(Warning: "{name}: {e}"))
+            .ok()?;
+        Some(from_variable(&found))
+    }
+}
+
+#[register]
+impl BasicUdf for JsonQuery {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_query(doc, expression)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        Self::new(cfg, args, "json_query")
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        match self.search(args, "json_query") {
+            None | Some(Value::Null) => Ok(None),
+            Some(found) => {
+                self.res = found.to_string();
+                Ok(Some(&self.res))
+            }
+        }
+    }
+}
+
+/// `json_query` that unwraps scalar results
+#[derive(Debug, Default)]
+struct JsonQueryScalar(JsonQuery);
+
+#[register]
+impl BasicUdf for JsonQueryScalar {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_query_scalar(doc, expression)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        JsonQuery::new(cfg, args, "json_query_scalar").map(Self)
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        self.0.res = match self.0.search(args, "json_query_scalar") {
+            Some(Value::String(s)) => s,
+            Some(found @ (Value::Number(_) | Value::Bool(_))) => found.to_string(),
+            _ => return Ok(None),
+        };
+        Ok(Some(&self.0.res))
+    }
+}
+
+/// Convert a value to the JMESPath representation
+///
+/// This is done by hand because serializing `Number` with `arbitrary_precision`
+/// doesn't round trip through other serde formats.
+fn to_variable(value: Value) -> Variable {
+    match value {
+        Value::Null => Variable::Null,
+        Value::Bool(v) => Variable::Bool(v),
+        Value::Number(v) => Variable::Number(v),
+        Value::String(v) => Variable::String(v),
+        Value::Array(arr) => Variable::Array(
+            arr.into_iter()
+                .map(|v| Rcvar::new(to_variable(v)))
+                .collect(),
+        ),
+        Value::Object(map) => Variable::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, Rcvar::new(to_variable(v))))
+                .collect(),
+        ),
+    }
+}
+
+/// Convert a JMESPath result back to a value
+fn from_variable(var: &Variable) -> Value {
+    match var {
+        Variable::Null | Variable::Expref(_) => Value::Null,
+        Variable::Bool(v) => Value::Bool(*v),
+        Variable::Number(v) => Value::Number(v.clone()),
+        Variable::String(v) => Value::String(v.clone()),
+        Variable::Array(arr) => arr.iter().map(|v| from_variable(v)).collect(),
+        Variable::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), from_variable(v)))
+                .collect::<Map<_, _>>(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udf::mock::*;
+
+    use super::*;
+
+    const DOC: &str = r#"{
+        "name": "widget",
+        "price": 10.50,
+        "active": true,
+        "tags": ["a", "b"],
+        "items": [
+            {"sku": "x1", "qty": 3, "meta": {"b": 1, "a": 2}},
+            {"sku": "x2", "qty": 0},
+            {"sku": "x3", "qty": 7}
+        ]
+    }"#;
+
+    /// Run a query with a constant expression
+    fn run(expr: &str, scalar: bool) -> Result<Option<String>, ProcessError> {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(DOC, "doc", false), (expr, "expr", false)];
+
+        if scalar {
+            let mut udf = JsonQueryScalar::init(cfg.as_init(), args.as_init()).unwrap();
+            assert!(udf.0.expr.is_some());
+            let res = udf.process(cfg.as_process(), args.as_process(), None);
+            res.map(|v| v.map(ToOwned::to_owned))
+        } else {
+            let mut udf = JsonQuery::init(cfg.as_init(), args.as_init()).unwrap();
+            assert!(udf.expr.is_some());
+            let res = udf.process(cfg.as_process(), args.as_process(), None);
+            res.map(|v| v.map(ToOwned::to_owned))
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let q = |expr| run(expr, false).unwrap();
+        assert_eq!(q("name").as_deref(), Some(r#""widget""#));
+        assert_eq!(q("price").as_deref(), Some("10.50"));
+        assert_eq!(
+            q("items[?qty > `0`].sku").as_deref(),
+            Some(r#"["x1","x3"]"#)
+        );
+        assert_eq!(
+            q("items[0].{s: sku, m: meta}").as_deref(),
+            Some(r#"{"m":{"a":2,"b":1},"s":"x1"}"#)
+        );
+        assert_eq!(q("length(tags)").as_deref(), Some("2"));
+        assert_eq!(q("sum(items[].qty)").as_deref(), Some("10.0"));
+        assert_eq!(q("missing"), None);
+    }
+
+    #[test]
+    fn test_query_scalar() {
+        let q = |expr| run(expr, true).unwrap();
+        assert_eq!(q("name").as_deref(), Some("widget"));
+        assert_eq!(q("price").as_deref(), Some("10.50"));
+        assert_eq!(q("active").as_deref(), Some("true"));
+        assert_eq!(q("items[0].sku").as_deref(), Some("x1"));
+        assert_eq!(q("tags"), None);
+        assert_eq!(q("missing"), None);
+    }
+
+    #[test]
+    fn test_non_const() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(DOC, "doc", false), (String None, "expr", true)];
+        let mut udf = JsonQuery::init(cfg.as_init(), args.as_init()).unwrap();
+        assert!(udf.expr.is_none());
+
+        let mut args = mock_args![(DOC, "doc", false), ("tags[1]", "expr", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#""b""#)));
+
+        let mut args = mock_args![(DOC, "doc", false), ("items[", "expr", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        // Later rows are unaffected
+        let mut args = mock_args![(DOC, "doc", false), ("tags[0]", "expr", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#""a""#)));
+
+        let mut args = mock_args![("{not json", "doc", false), ("tags", "expr", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+
+        let mut args = mock_args![(String None, "doc", true), ("tags", "expr", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+    }
+
+    #[test]
+    fn test_init_errors() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(DOC, "doc", false), ("items[", "expr", false)];
+        assert!(JsonQuery::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(DOC, "doc", false)];
+        assert!(JsonQuery::init(cfg.as_init(), args.as_init()).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! Validating documents against a [JSON Schema]
+//!
+//! Schemas use draft 2020-12 unless they declare another draft with
+//! `$schema`. Remote references are not fetched.
+//!
+//! A constant schema is compiled once in `init`, and an invalid one is reported
+//! there. A non-constant schema is compiled when it changes from the previous
+//! row, and an invalid one returns `NULL` with a warning.
+//!
+//! A `NULL` document returns `NULL`. A document that is not valid JSON is
+//! invalid rather than `NULL`, so that it is rejected by `CHECK` constraints.
+//!
+//! [JSON Schema]: https://json-schema.org/
+
+use jsonschema::Validator;
+use serde_json::{json, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+/// Shared state for the schema functions
+#[derive(Debug, Default)]
+struct SchemaCheck {
+    validator: Option<Validator>,
+    /// Source of the current validator, if the schema is not constant
+    schema_src: Option<Vec<u8>>,
+    res: String,
+}
+
+/// Compile a schema from JSON text
+fn compile(src: &[u8]) -> Result<Validator, String> {
+    let schema: Value =
+        serde_json::from_slice(src).map_err(|e| format!("schema is not valid JSON: {e}"))?;
+    jsonschema::validator_for(&schema).map_err(|e| format!("invalid schema: {e}"))
+}
+
+impl SchemaCheck {
+    fn new(args: &ArgList<Init>, name: &str) -> Result<Self, String> {
+        if args.len() != 2 {
+            return Err(format!(
+                "{name} takes 2 arguments but got {} (usage: `{name}(doc, schema)`)",
+                args.len()
+            ));
+        }
+
+        // The value is only available during `init` if it is constant
+        let validator = match args.get(1).unwrap().value().as_bytes() {
+            Some(src) => Some(compile(src).map_err(|e| format!("{name}: {e}"))?),
+            None => None,
+        };
+
+        for mut arg in args {
+            arg.set_type_coercion(SqlType::String);
+        }
+
+        Ok(Self {
+            validator,
+            ..Default::default()
+        })
+    }
+
+    /// Validate the document, returning a list of errors, or `None` if either
+    /// argument is `NULL` or the schema is invalid
+    fn check(&mut self, args: &ArgList<Process>, name: &str) -> Option<Vec<Value>> {
+        let doc_arg = args.get(0).unwrap().value();
+        let doc_src = doc_arg.as_bytes()?;
+
+        if self.validator.is_none() || self.schema_src.is_some() {
+            let schema_arg = args.get(1).unwrap().value();
+            let schema_src = schema_arg.as_bytes()?;
+            if self.schema_src.as_deref() != Some(schema_src) {
+                let validator = compile(schema_src)
+                    .map_err(|e| udf_log!(Warning: "{name}: {e}"))
+                    .ok()?;
+                self.validator = Some(validator);
+                self.schema_src = Some(schema_src.to_owned());
+            }
+        }
+
+        let doc: Value = match serde_json::from_slice(doc_src) {
+            Ok(v) => v,
+            Err(e) => {
+                let msg = format!("document is not valid JSON: {e}");
+                return Some(vec![json!({"path": "", "message": msg})]);
+            }
+        };
+
+        let validator = self.validator.as_ref().unwrap();
+        let errors = validator
+            .iter_errors(&doc)
+            .map(|e| json!({"path": e.instance_path().as_str(), "message": e.to_string()}))
+            .collect();
+        Some(errors)
+    }
+}
+
+/// Check whether a document matches a schema
+#[derive(Debug, Default)]
+struct JsonSchemaValid(SchemaCheck);
+
+#[register]
+impl BasicUdf for JsonSchemaValid {
+    type Returns<'a> = Option<i64>;
+
+    /// Expect `json_schema_valid(doc, schema)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let check = SchemaCheck::new(args, "json_schema_valid")?;
+        cfg.set_maybe_null(true);
+        Ok(Self(check))
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let errors = self.0.check(args, "json_schema_valid");
+        Ok(errors.map(|e| i64::from(e.is_empty())))
+    }
+}
+
+/// List the ways a document doesn't match a schema
+#[derive(Debug, Default)]
+struct JsonSchemaErrors(SchemaCheck);
+
+#[register]
+impl BasicUdf for JsonSchemaErrors {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `json_schema_errors(doc, schema)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let check = SchemaCheck::new(args, "json_schema_errors")?;
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        cfg.set_maybe_null(true);
+        Ok(Self(check))
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let Some(errors) = self.0.check(args, "json_schema_errors") else {
+            return Ok(None);
+        };
+        self.0.res = Value::Array(errors).to_string();
+        Ok(Some(&self.0.res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udf::mock::*;
+
+    use super::*;
+
+    const SCHEMA: &str = r#"{
+        "type": "object",
+        "properties": {
+            "id": {"type": "integer", "minimum": 1},
+            "price": {"type": "number", "exclusiveMaximum": 100},
+            "tags": {"type": "array", "items": {"type": "string"}}
+        },
+        "required": ["id"]
+    }"#;
+
+    #[test]
+    fn test_valid() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(String None, "doc", true), (SCHEMA, "schema", false)];
+        let mut udf = JsonSchemaValid::init(cfg.as_init(), args.as_init()).unwrap();
+        assert!(udf.0.validator.is_some());
+
+        let cases = [
+            (r#"{"id": 1, "price": 99.99, "tags": ["a"]}"#, Some(1)),
+            (r#"{"id": 0}"#, Some(0)),
+            (r#"{"price": 1}"#, Some(0)),
+            (r#"{"id": 1, "price": 100.0}"#, Some(0)),
+            (r#"{"id": 1, "tags": [1]}"#, Some(0)),
+            ("{not json", Some(0)),
+        ];
+        for (doc, expected) in cases {
+            let mut args = mock_args![(doc, "doc", false), (SCHEMA, "schema", false)];
+            let res = udf.process(cfg.as_process(), args.as_process(), None);
+            assert_eq!(res, Ok(expected), "{doc}");
+        }
+
+        let mut args = mock_args![(String None, "doc", true), (SCHEMA, "schema", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![
+            (r#"{"id": 0, "tags": ["a", 2]}"#, "doc", false),
+            (SCHEMA, "schema", false)
+        ];
+        let mut udf = JsonSchemaErrors::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        let errors: Value = serde_json::from_str(res.unwrap().unwrap()).unwrap();
+        let paths: Vec<_> = errors
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["/id", "/tags/1"]);
+        assert!(errors[0]["message"].as_str().unwrap().contains("minimum"));
+
+        let mut args = mock_args![(r#"{"id": 5}"#, "doc", false), (SCHEMA, "schema", false)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some("[]")));
+    }
+
+    #[test]
+    fn test_schema_changes() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(String None, "doc", true), (String None, "schema", true)];
+        let mut udf = JsonSchemaValid::init(cfg.as_init(), args.as_init()).unwrap();
+        assert!(udf.0.validator.is_none());
+
+        for (schema, expected) in [
+            (r#"{"type": "string"}"#, Some(1)),
+            (r#"{"type": "string"}"#, Some(1)),
+            (r#"{"type": "number"}"#, Some(0)),
+        ] {
+            let mut args = mock_args![(r#""abc""#, "doc", false), (schema, "schema", false)];
+            let res = udf.process(cfg.as_process(), args.as_process(), None);
+            assert_eq!(res, Ok(expected));
+        }
+
+        let mut args = mock_args![
+            (r#""abc""#, "doc", false),
+            (r#"{"type": 5}"#, "schema", false)
+        ];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+    }
+
+    #[test]
+    fn test_init_errors() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(String None, "doc", true), (r#"{"type": 5}"#, "schema", false)];
+        assert!(JsonSchemaValid::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(String None, "doc", true), ("{", "schema", false)];
+        assert!(JsonSchemaErrors::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(String None, "doc", true)];
+        assert!(JsonSchemaValid::init(cfg.as_init(), args.as_init()).is_err());
+    }
+}
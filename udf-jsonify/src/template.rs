@@ -0,0 +1,370 @@
+//! Output templates built from argument keys
+//!
+//! Keys can be paths, which are parsed once during `init` into a tree that
+//! `process` fills with values. Path segments are separated by `.` and may be
+//! followed by array indices, so `user.name`, `tags[0]` and `items[1].id` all
+//! describe nested locations. Keys that are not valid paths (e.g. `a..b` or
+//! `x[y]`) are used as-is.
+
+use serde_json::{Map, Value};
+
+use crate::options::Duplicates;
+
+/// Refuse to allocate arrays larger than this
+const MAX_INDEX: usize = u16::MAX as usize;
+
+/// A document type that a template can be filled into
+pub trait Document: Sized {
+    /// The value of array items that have no argument
+    fn null() -> Self;
+    /// An object with the given entries, in order
+    fn object(entries: Vec<(String, Self)>) -> Self;
+    /// An array with the given items
+    fn array(items: Vec<Self>) -> Self;
+}
+
+impl Document for Value {
+    fn null() -> Self {
+        Value::Null
+    }
+
+    fn object(entries: Vec<(String, Self)>) -> Self {
+        Value::Object(entries.into_iter().collect::<Map<_, _>>())
+    }
+
+    fn array(items: Vec<Self>) -> Self {
+        Value::Array(items)
+    }
+}
+
+/// A single step in a key path
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// Parse a key into path segments, returning `None` if it is not a valid path
+pub fn parse_path(key: &str) -> Option<Vec<Segment>> {
+    parse_path_with(key, ".")
+}
+
+/// Parse a key into path segments using a separator other than `.`
+pub fn parse_path_with(key: &str, sep: &str) -> Option<Vec<Segment>> {
+    let mut ret = Vec::new();
+
+    for part in key.split(sep) {
+        let (name, mut rest) = match part.find('[') {
+            Some(pos) => part.split_at(pos),
+            None => (part, ""),
+        };
+
+        if name.is_empty() {
+            return None;
+        }
+        ret.push(Segment::Key(name.to_owned()));
+
+        while !rest.is_empty() {
+            let (idx_str, remaining) = rest.strip_prefix('[')?.split_once(']')?;
+            if idx_str.is_empty() || !idx_str.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let idx: usize = idx_str.parse().ok()?;
+            if idx > MAX_INDEX {
+                return None;
+            }
+            ret.push(Segment::Index(idx));
+            rest = remaining;
+        }
+    }
+
+    Some(ret)
+}
+
+/// A location in the output, either a value or a container
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    /// Filled from the argument at this index
+    Leaf(usize),
+    Object(Vec<(String, Node)>),
+    Array(Vec<Option<Node>>),
+}
+
+impl Node {
+    fn describe(&self) -> &'static str {
+        match self {
+            Self::Leaf(_) => "a value",
+            Self::Object(_) => "an object",
+            Self::Array(_) => "an array",
+        }
+    }
+
+    /// Create an empty node of the kind that `seg` indexes into
+    fn container_for(seg: &Segment) -> Self {
+        match seg {
+            Segment::Key(_) => Self::Object(Vec::new()),
+            Segment::Index(_) => Self::Array(Vec::new()),
+        }
+    }
+
+    /// Get the child at `seg`, creating it with `default` if it doesn't exist
+    fn child_or_insert(
+        &mut self,
+        seg: &Segment,
+        default: impl FnOnce() -> Self,
+        key: &str,
+    ) -> Result<(&mut Self, bool), String> {
+        match (self, seg) {
+            (Self::Object(children), Segment::Key(name)) => {
+                let pos = match children.iter().position(|(k, _)| k == name) {
+                    Some(pos) => (pos, false),
+                    None => {
+                        children.push((name.clone(), default()));
+                        (children.len() - 1, true)
+                    }
+                };
+                Ok((&mut children[pos.0].1, pos.1))
+            }
+            (Self::Array(items), Segment::Index(idx)) => {
+                if items.len() <= *idx {
+                    items.resize(*idx + 1, None);
+                }
+                let created = items[*idx].is_none();
+                Ok((items[*idx].get_or_insert_with(default), created))
+            }
+            (node, _) => Err(format!(
+                "key '{key}' conflicts with another key that uses this location as {}",
+                node.describe()
+            )),
+        }
+    }
+
+    /// Insert a leaf for `arg_idx` at `path`, relative to this node
+    fn insert(
+        &mut self,
+        path: &[Segment],
+        arg_idx: usize,
+        key: &str,
+        duplicates: Duplicates,
+    ) -> Result<(), String> {
+        let Some((seg, rest)) = path.split_first() else {
+            return Ok(());
+        };
+        let next = rest.first();
+        let (child, created) = self.child_or_insert(
+            seg,
+            || next.map_or(Node::Leaf(arg_idx), Node::container_for),
+            key,
+        )?;
+
+        match (next, child) {
+            (None, Self::Leaf(existing)) if !created => match duplicates {
+                Duplicates::Error => Err(format!(
+                    "duplicate key '{key}'; use an alias or the 'duplicates' option"
+                )),
+                Duplicates::First => Ok(()),
+                Duplicates::Last => {
+                    *existing = arg_idx;
+                    Ok(())
+                }
+            },
+            (None, Self::Leaf(_)) => Ok(()),
+            (None, other) => Err(format!(
+                "key '{key}' conflicts with another key that uses this location as {}",
+                other.describe()
+            )),
+            (Some(_), child) => child.insert(rest, arg_idx, key, duplicates),
+        }
+    }
+
+    fn sort(&mut self) {
+        match self {
+            Self::Leaf(_) => (),
+            Self::Object(children) => {
+                children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                children.iter_mut().for_each(|(_, child)| child.sort());
+            }
+            Self::Array(items) => items.iter_mut().flatten().for_each(Self::sort),
+        }
+    }
+
+    /// Build the value for this node, or `None` if it should be left out
+    fn build<T: Document, E>(
+        &self,
+        f: &mut impl FnMut(usize) -> Result<Option<T>, E>,
+    ) -> Result<Option<T>, E> {
+        let ret = match self {
+            Self::Leaf(idx) => return f(*idx),
+            Self::Object(children) => {
+                let mut entries = Vec::with_capacity(children.len());
+                for (k, child) in children {
+                    if let Some(v) = child.build(f)? {
+                        entries.push((k.clone(), v));
+                    }
+                }
+                T::object(entries)
+            }
+            // Omitted array items become `null` so that indices are preserved
+            Self::Array(items) => T::array(
+                items
+                    .iter()
+                    .map(|item| match item {
+                        Some(node) => Ok(node.build(f)?.unwrap_or_else(T::null)),
+                        None => Ok(T::null()),
+                    })
+                    .collect::<Result<_, E>>()?,
+            ),
+        };
+        Ok(Some(ret))
+    }
+}
+
+/// A tree of output locations, with an object at the root
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template(Node);
+
+impl Default for Template {
+    fn default() -> Self {
+        Self(Node::Object(Vec::new()))
+    }
+}
+
+impl Template {
+    /// Add a key that is filled from the argument at `arg_idx`
+    ///
+    /// If `as_path` is set, the key is split into a path if possible.
+    pub fn insert(
+        &mut self,
+        key: &str,
+        arg_idx: usize,
+        as_path: bool,
+        duplicates: Duplicates,
+    ) -> Result<(), String> {
+        let path = as_path
+            .then(|| parse_path(key))
+            .flatten()
+            .unwrap_or_else(|| vec![Segment::Key(key.to_owned())]);
+
+        self.insert_path(&path, key, arg_idx, duplicates)
+    }
+
+    /// Add an already parsed path that is filled from the argument at `arg_idx`
+    ///
+    /// `key` is only used for error messages.
+    pub fn insert_path(
+        &mut self,
+        path: &[Segment],
+        key: &str,
+        arg_idx: usize,
+        duplicates: Duplicates,
+    ) -> Result<(), String> {
+        self.0.insert(path, arg_idx, key, duplicates)
+    }
+
+    /// Recursively sort all object keys
+    pub fn sort(&mut self) {
+        self.0.sort();
+    }
+
+    /// Create a document, using `f` to get the value for each argument index
+    ///
+    /// `f` may return `None` to leave a value out of its object.
+    pub fn build<T: Document, E>(
+        &self,
+        mut f: impl FnMut(usize) -> Result<Option<T>, E>,
+    ) -> Result<T, E> {
+        Ok(self.0.build(&mut f)?.unwrap_or_else(T::null))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        use Segment::{Index, Key};
+
+        assert_eq!(parse_path("a"), Some(vec![Key("a".to_owned())]));
+        assert_eq!(
+            parse_path("user.name"),
+            Some(vec![Key("user".to_owned()), Key("name".to_owned())])
+        );
+        assert_eq!(
+            parse_path("items[1].ids[0][2]"),
+            Some(vec![
+                Key("items".to_owned()),
+                Index(1),
+                Key("ids".to_owned()),
+                Index(0),
+                Index(2)
+            ])
+        );
+        assert_eq!(parse_path("a..b"), None);
+        assert_eq!(parse_path(".a"), None);
+        assert_eq!(parse_path("[0]"), None);
+        assert_eq!(parse_path("a[x]"), None);
+        assert_eq!(parse_path("a[0"), None);
+        assert_eq!(parse_path("a[0]b"), None);
+        assert_eq!(parse_path("a[99999999]"), None);
+
+        assert_eq!(
+            parse_path_with("a/b[0]", "/"),
+            Some(vec![Key("a".to_owned()), Key("b".to_owned()), Index(0)])
+        );
+        assert_eq!(
+            parse_path_with("a.b", "__"),
+            Some(vec![Key("a.b".to_owned())])
+        );
+    }
+
+    #[test]
+    fn test_build() {
+        let mut tpl = Template::default();
+        for (idx, key) in ["user.name", "user.id", "tags[1]", "tags[0]", "x..y"]
+            .iter()
+            .enumerate()
+        {
+            tpl.insert(key, idx, true, Duplicates::Error).unwrap();
+        }
+
+        let res = tpl
+            .build(|idx| Ok::<_, ()>(Some(Value::from(idx))))
+            .unwrap();
+        assert_eq!(
+            res.to_string(),
+            r#"{"user":{"name":0,"id":1},"tags":[3,2],"x..y":4}"#
+        );
+
+        tpl.sort();
+        let res = tpl
+            .build(|idx| Ok::<_, ()>(Some(Value::from(idx))))
+            .unwrap();
+        assert_eq!(
+            res.to_string(),
+            r#"{"tags":[3,2],"user":{"id":1,"name":0},"x..y":4}"#
+        );
+
+        // Leave out odd indices
+        let res = tpl
+            .build(|idx| Ok::<_, ()>((idx % 2 == 0).then(|| Value::from(idx))))
+            .unwrap();
+        assert_eq!(
+            res.to_string(),
+            r#"{"tags":[null,2],"user":{"name":0},"x..y":4}"#
+        );
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let mut tpl = Template::default();
+        tpl.insert("a.b", 0, true, Duplicates::Last).unwrap();
+        assert!(tpl.insert("a", 1, true, Duplicates::Last).is_err());
+        assert!(tpl.insert("a[0]", 1, true, Duplicates::Last).is_err());
+        assert!(tpl.insert("a.b.c", 1, true, Duplicates::Last).is_err());
+        assert!(tpl.insert("a.b", 1, true, Duplicates::Error).is_err());
+
+        // Without path parsing these are just flat keys
+        tpl.insert("a.b.c", 1, false, Duplicates::Error).unwrap();
+    }
+}
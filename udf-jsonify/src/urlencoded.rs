@@ -0,0 +1,213 @@
+//! `application/x-www-form-urlencoded` query strings
+//!
+//! `urlencodify` builds a query string from its arguments, using attribute
+//! names as keys the same way as `jsonify`. Every byte other than ASCII
+//! alphanumerics and `*-._` is percent encoded and spaces become `+`, so binary
+//! data is safe. `NULL` arguments are left out.
+//!
+//! `querystring_to_json` parses a query string into an object of strings. Keys
+//! that appear more than once are collected into an array, at the position of
+//! their first appearance. A leading `?` is ignored, pairs without `=` have an
+//! empty value, and invalid percent escapes are kept as-is. Decoded bytes that
+//! are not valid UTF-8 are replaced with U+FFFD.
+
+use std::fmt::Write;
+
+use serde_json::{Map, Value};
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+/// Append `bytes` to `out`, encoded as a form component
+fn push_encoded(out: &mut String, bytes: &[u8]) {
+    for &b in bytes {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => {
+                out.push(b.into());
+            }
+            b' ' => out.push('+'),
+            _ => write!(out, "%{b:02X}").unwrap(),
+        }
+    }
+}
+
+/// Decode a form component
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                ret.push(b);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => ret.push(b' '),
+            (b, _) => ret.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Parse a query string into an object
+pub fn parse_query(qs: &str) -> Map<String, Value> {
+    let mut ret = Map::new();
+    let qs = qs.strip_prefix('?').unwrap_or(qs);
+
+    for pair in qs.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let (key, value) = (decode(key), Value::String(decode(value)));
+        match ret.get_mut(&key) {
+            Some(Value::Array(arr)) => arr.push(value),
+            Some(existing) => {
+                let first = existing.take();
+                *existing = Value::Array(vec![first, value]);
+            }
+            None => {
+                ret.insert(key, value);
+            }
+        }
+    }
+
+    ret
+}
+
+/// Create a query string from all arguments
+#[derive(Debug, Default)]
+struct Urlencodify(String);
+
+#[register]
+impl BasicUdf for Urlencodify {
+    type Returns<'a> = &'a str;
+
+    fn init(cfg: &UdfCfg<Init>, _args: &ArgList<Init>) -> Result<Self, String> {
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        self.0.clear();
+
+        for arg in args {
+            let value = match arg.value() {
+                SqlResult::String(Some(v)) => v.to_owned(),
+                SqlResult::Real(Some(v)) => v.to_string().into_bytes(),
+                SqlResult::Int(Some(v)) => v.to_string().into_bytes(),
+                SqlResult::Decimal(Some(v)) => v.as_bytes().to_owned(),
+                _ => continue,
+            };
+            if !self.0.is_empty() {
+                self.0.push('&');
+            }
+            push_encoded(&mut self.0, arg.attribute().as_bytes());
+            self.0.push('=');
+            push_encoded(&mut self.0, &value);
+        }
+
+        Ok(&self.0)
+    }
+}
+
+/// Parse a query string to a JSON object
+#[derive(Debug, Default)]
+struct QuerystringToJson(String);
+
+#[register]
+impl BasicUdf for QuerystringToJson {
+    type Returns<'a> = Option<&'a str>;
+
+    /// Expect `querystring_to_json(qs)`
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        if args.len() != 1 {
+            return Err(format!(
+                "querystring_to_json takes 1 argument but got {} (usage: \
+                `querystring_to_json(qs)`)",
+                args.len()
+            ));
+        }
+        args.get(0).unwrap().set_type_coercion(SqlType::String);
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        cfg.set_maybe_null(true);
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let arg = args.get(0).unwrap().value();
+        let Some(bytes) = arg.as_bytes() else {
+            return Ok(None);
+        };
+        let qs = String::from_utf8_lossy(bytes);
+        self.0 = Value::Object(parse_query(&qs)).to_string();
+        Ok(Some(&self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut out = String::new();
+        push_encoded(&mut out, "a b&c=d/é*-._~".as_bytes());
+        assert_eq!(out, "a+b%26c%3Dd%2F%C3%A9*-._%7E");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Value::Object(parse_query(
+                "?utm_source=mail&tag=a&q=x+y%21&tag=b&flag&tag=c"
+            )),
+            json!({"utm_source": "mail", "tag": ["a", "b", "c"], "q": "x y!", "flag": ""})
+        );
+        assert_eq!(
+            Value::Object(parse_query("a=%zz%4&&b%20c=%FF&d=%+1")),
+            json!({"a": "%zz%4", "b c": "\u{fffd}", "d": "% 1"})
+        );
+        assert_eq!(Value::Object(parse_query("")), json!({}));
+    }
+
+    #[test]
+    fn test_udfs() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![
+            ("new user", "utm_campaign", false),
+            (42, "id", false),
+            (String None, "ref", true),
+            (1.5, "w", false),
+            (&b"\x00\xff"[..], "data key", false),
+        ];
+        let mut udf = Urlencodify::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok("utm_campaign=new+user&id=42&w=1.5&data+key=%00%FF"));
+
+        let mut args = mock_args![("a=1&b=2&a=3", "qs", false)];
+        let mut udf = QuerystringToJson::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(Some(r#"{"a":["1","3"],"b":"2"}"#)));
+
+        let mut args = mock_args![(String None, "qs", true)];
+        let res = udf.process(cfg.as_process(), args.as_process(), None);
+        assert_eq!(res, Ok(None));
+    }
+}
@@ -1,32 +1,77 @@
-//! A function to generate lipsum of a given word count
+//! Functions to generate lipsum of a given word, character, sentence or
+//! paragraph count
 //!
 //! # Usage
 //!
 //! ```sql
 //! CREATE FUNCTION lipsum RETURNS string SONAME 'libudf_lipsum.so';
+//! CREATE FUNCTION lipsum_chars RETURNS string SONAME 'libudf_lipsum.so';
+//! CREATE FUNCTION lipsum_sentences RETURNS string SONAME 'libudf_lipsum.so';
+//! CREATE FUNCTION lipsum_paragraphs RETURNS string SONAME 'libudf_lipsum.so';
 //! SELECT lipsum(8);
+//! SELECT lipsum(5, 20, 42); -- 5 to 20 words, different for each row
+//! SELECT lipsum_chars(40);
+//! SELECT lipsum_paragraphs(3, '<br>');
 //! ```
 
 use std::num::NonZeroU8;
 
-use lipsum::{lipsum as lipsum_fn, lipsum_from_seed};
+use lipsum::{lipsum as lipsum_fn, lipsum_from_seed, lipsum_words_from_seed};
 use udf::prelude::*;
 
 // Cap potential resource usage, this gives us more than enough to
 // populate LONGTEXT
 const MAX_WORDS: i64 = (u32::MAX >> 4) as i64;
 
+// The same sort of cap for the other units, chosen so that generating them
+// never needs more than `MAX_WORDS` words. Words average about 6.8 bytes, so
+// `MAX_WORDS` words always cover `MAX_CHARS`, and `lipsum_to_sentences` stops
+// looking after `MAX_WORDS_PER_SENTENCE` words per sentence.
+const MAX_CHARS: i64 = MAX_WORDS * 4;
+const MAX_SENTENCES: i64 = MAX_WORDS / MAX_WORDS_PER_SENTENCE as i64;
+const MAX_PARAGRAPHS: i64 = MAX_SENTENCES / SENTENCES_PER_PARAGRAPH as i64;
+
+/// Expected number of words per sentence, slightly above the average of about
+/// 22.7 so that the first attempt usually suffices
+const WORDS_PER_SENTENCE: usize = 25;
+
+/// Number of words per sentence after which `lipsum_to_sentences` accepts
+/// whatever it has
+const MAX_WORDS_PER_SENTENCE: usize = 64;
+
+/// Number of sentences in each paragraph from `lipsum_paragraphs`
+const SENTENCES_PER_PARAGRAPH: usize = 5;
+
+/// Default separator between paragraphs
+const DEFAULT_PARAGRAPH_SEP: &str = "\n\n";
+
+/// Create a seed for a single row from the seed argument
+///
+/// This is one step of SplitMix64, which gives well spread values even for
+/// consecutive rows.
+fn row_seed(seed: u64, row: u64) -> u64 {
+    let mut z = seed.wrapping_add(row.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
 /// We expect to return a long string here so we need to contain it in
-struct Lipsum(String);
+#[derive(Debug, Default)]
+struct Lipsum {
+    res: String,
+    /// Number of rows processed, used to vary output with `LIPSUM(min, max, seed)`
+    row: u64,
+}
 
 #[register]
 impl BasicUdf for Lipsum {
     type Returns<'a> = &'a str;
 
-    /// We expect LIPSUM(n) or LIPSUM(n, m)
+    /// We expect LIPSUM(n), LIPSUM(n, seed) or LIPSUM(min, max, seed)
     fn init(_cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
-        if args.is_empty() || args.len() > 2 {
-            return Err(format!("Expected 1 or 2 args; got {}", args.len()));
+        if args.is_empty() || args.len() > 3 {
+            return Err(format!("Expected 1 to 3 args; got {}", args.len()));
         }
 
         let n = args
@@ -44,6 +89,26 @@ impl BasicUdf for Lipsum {
             return Err(format!("Word count must be greater than 0, got {n}"));
         }
 
+        // With a range, the second arg is the maximum word count
+        if args.len() == 3 {
+            let max = args
+                .get(1)
+                .unwrap()
+                .value()
+                .as_int()
+                .ok_or_else(|| "Second argument must be an integer".to_owned())?;
+            if max > MAX_WORDS {
+                return Err(format!("Maximum of {MAX_WORDS} words, got {max}"));
+            }
+            if max < n {
+                return Err(format!(
+                    "Maximum word count must be at least the minimum {n}, got {max}"
+                ));
+            }
+            check_seed(args, 2)?;
+            return Ok(Self::default());
+        }
+
         // If there is an extra arg, verify it is also an integer
         if let Some(v) = args.get(1) {
             let seed = v
@@ -55,7 +120,7 @@ impl BasicUdf for Lipsum {
             }
         };
 
-        Ok(Self(String::default()))
+        Ok(Self::default())
     }
 
     fn process<'a>(
@@ -74,6 +139,20 @@ impl BasicUdf for Lipsum {
             .ok_or(ProcessError)?
             .unsigned_abs() as usize;
 
+        if args.len() == 3 {
+            let max = args.get(1).unwrap().value().as_int().ok_or(ProcessError)?;
+            let seed = args.get(2).unwrap().value().as_int().ok_or(ProcessError)?;
+            let seed = row_seed(seed.unsigned_abs(), self.row);
+            self.row += 1;
+
+            // Pick a length in `n..=max`, then start from a random word so
+            // that rows differ in content as well as length
+            let span = max.unsigned_abs() - n as u64 + 1;
+            let len = n + (seed % span) as usize;
+            self.res = lipsum_words_from_seed(len, seed);
+            return Ok(&self.res);
+        }
+
         let res = match args.get(1) {
             Some(v) => {
                 // If we have a seed argument, use it.
@@ -86,8 +165,380 @@ impl BasicUdf for Lipsum {
             }
         };
 
-        self.0 = res;
+        self.res = res;
+
+        Ok(&self.res)
+    }
+}
+
+/// Check that the first argument is a constant integer between 0 and `max`
+fn check_count(args: &ArgList<Init>, max: i64, unit: &str) -> Result<(), String> {
+    let n = args
+        .get(0)
+        .unwrap()
+        .value()
+        .as_int()
+        .ok_or_else(|| "First argument must be an integer".to_owned())?;
+
+    if n > max {
+        return Err(format!("Maximum of {max} {unit}, got {n}"));
+    }
+    if n < 0 {
+        return Err(format!("Count must be greater than 0, got {n}"));
+    }
+    Ok(())
+}
+
+/// Check that argument `idx`, if present, is a valid seed
+fn check_seed(args: &ArgList<Init>, idx: usize) -> Result<(), String> {
+    if let Some(v) = args.get(idx) {
+        let seed = v
+            .value()
+            .as_int()
+            .ok_or_else(|| "Seed must be an integer".to_owned())?;
+        if seed < 0 {
+            return Err(format!("Seed must be a positive integer, got {seed}"));
+        }
+    }
+    Ok(())
+}
+
+/// Get the count argument, which was checked in `init`
+fn get_count(args: &ArgList<Process>) -> Result<usize, ProcessError> {
+    let n = args.get(0).unwrap().value().as_int().ok_or(ProcessError)?;
+    Ok(n.unsigned_abs() as usize)
+}
+
+/// Generate text of at most `n` characters that ends on a word boundary
+///
+/// `gen` creates text with a given number of words. Trailing punctuation
+/// other than a sentence end is removed.
+fn lipsum_to_chars(n: usize, gen: impl Fn(usize) -> String) -> String {
+    if n == 0 {
+        return String::new();
+    }
+
+    // Words average about 6.8 bytes including the space, so this is almost
+    // always enough on the first try. `MAX_CHARS` makes sure that `MAX_WORDS`
+    // words are enough in any case.
+    let max_words = MAX_WORDS as usize;
+    let mut words = (n / 4 + 1).min(max_words);
+    loop {
+        let mut text = gen(words);
+        let cut = match text.char_indices().nth(n) {
+            Some((cut, _)) => cut,
+            None if words < max_words => {
+                words = (words * 2).min(max_words);
+                continue;
+            }
+            None => text.len(),
+        };
+
+        let end = if cut == text.len() || text[cut..].starts_with(' ') {
+            cut
+        } else {
+            text[..cut].rfind(' ').unwrap_or(0)
+        };
+        text.truncate(end);
+        let trimmed = text.trim_end_matches([',', ';', ':']).len();
+        text.truncate(trimmed);
+        return text;
+    }
+}
+
+/// Generate text with exactly `n` sentences, returning the text and the byte
+/// offset of the end of each sentence
+fn lipsum_to_sentences(n: usize) -> (String, Vec<usize>) {
+    if n == 0 {
+        return (String::new(), Vec::new());
+    }
+
+    let limit = (n * MAX_WORDS_PER_SENTENCE).min(MAX_WORDS as usize);
+    let mut words = (n * WORDS_PER_SENTENCE).min(limit);
+    loop {
+        let mut text = lipsum_fn(words);
+        let ends: Vec<usize> = text
+            .match_indices(['.', '!', '?'])
+            .map(|(i, _)| i + 1)
+            .filter(|&i| i == text.len() || text[i..].starts_with(' '))
+            .take(n)
+            .collect();
+
+        // The final sentence may have been cut short and given a period, so
+        // only accept it if it is the one we need. At the limit, this is
+        // practically certain to hold n sentences anyway.
+        if (ends.len() == n && ends[n - 1] < text.len()) || words == limit {
+            if let Some(&end) = ends.last() {
+                text.truncate(end);
+            }
+            return (text, ends);
+        }
+        words = (words * 2).min(limit);
+    }
+}
+
+/// Generate lipsum with a maximum character count
+#[derive(Debug, Default)]
+struct LipsumChars(String);
+
+#[register]
+impl BasicUdf for LipsumChars {
+    type Returns<'a> = &'a str;
+
+    /// We expect LIPSUM_CHARS(n) or LIPSUM_CHARS(n, seed)
+    fn init(_cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(format!("Expected 1 or 2 args; got {}", args.len()));
+        }
+        check_count(args, MAX_CHARS, "characters")?;
+        check_seed(args, 1)?;
+        Ok(Self::default())
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let n = get_count(args)?;
+
+        self.0 = match args.get(1) {
+            Some(v) => {
+                let seed = v.value().as_int().ok_or(ProcessError)?.unsigned_abs();
+                lipsum_to_chars(n, |words| lipsum_from_seed(words, seed))
+            }
+            None => lipsum_to_chars(n, lipsum_fn),
+        };
+
+        Ok(&self.0)
+    }
+}
+
+/// Generate lipsum with a given sentence count
+#[derive(Debug, Default)]
+struct LipsumSentences(String);
+
+#[register]
+impl BasicUdf for LipsumSentences {
+    type Returns<'a> = &'a str;
+
+    /// We expect LIPSUM_SENTENCES(n)
+    fn init(_cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        if args.len() != 1 {
+            return Err(format!("Expected 1 arg; got {}", args.len()));
+        }
+        check_count(args, MAX_SENTENCES, "sentences")?;
+        Ok(Self::default())
+    }
 
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        self.0 = lipsum_to_sentences(get_count(args)?).0;
         Ok(&self.0)
     }
 }
+
+/// Generate lipsum with a given paragraph count
+#[derive(Debug, Default)]
+struct LipsumParagraphs {
+    sep: String,
+    res: String,
+}
+
+#[register]
+impl BasicUdf for LipsumParagraphs {
+    type Returns<'a> = &'a str;
+
+    /// We expect LIPSUM_PARAGRAPHS(n) or LIPSUM_PARAGRAPHS(n, sep)
+    fn init(_cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(format!("Expected 1 or 2 args; got {}", args.len()));
+        }
+        check_count(args, MAX_PARAGRAPHS, "paragraphs")?;
+
+        let sep = match args.get(1) {
+            Some(v) => v
+                .value()
+                .as_string()
+                .ok_or_else(|| "Separator must be a string".to_owned())?
+                .to_owned(),
+            None => DEFAULT_PARAGRAPH_SEP.to_owned(),
+        };
+
+        Ok(Self {
+            sep,
+            res: String::new(),
+        })
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let n = get_count(args)?;
+        let (text, ends) = lipsum_to_sentences(n * SENTENCES_PER_PARAGRAPH);
+
+        self.res.clear();
+        let mut start = 0;
+        for (i, &end) in ends
+            .iter()
+            .skip(SENTENCES_PER_PARAGRAPH - 1)
+            .step_by(SENTENCES_PER_PARAGRAPH)
+            .enumerate()
+        {
+            if i > 0 {
+                self.res.push_str(&self.sep);
+            }
+            self.res.push_str(text[start..end].trim_start());
+            start = end;
+        }
+
+        Ok(&self.res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_chars() {
+        for n in [0, 3, 5, 11, 40, 333] {
+            let text = lipsum_to_chars(n, |words| lipsum_from_seed(words, 7));
+            assert!(text.chars().count() <= n, "{n}: {text}");
+            assert!(!text.ends_with([' ', ',']), "{n}: {text}");
+            // Ends on a word boundary of the full text
+            let full = lipsum_from_seed(n, 7);
+            assert!(full.starts_with(&text), "{n}: {text}");
+        }
+        assert_eq!(lipsum_to_chars(4, lipsum_fn), "");
+        assert_eq!(lipsum_to_chars(11, lipsum_fn), "Lorem ipsum");
+        assert_eq!(lipsum_to_chars(12, lipsum_fn), "Lorem ipsum");
+        // Stops growing at `MAX_WORDS` even if the text stays short
+        assert_eq!(lipsum_to_chars(100, |_| "a b".to_owned()), "a b");
+    }
+
+    #[test]
+    fn test_sentences() {
+        for n in [1, 2, 10] {
+            let (text, ends) = lipsum_to_sentences(n);
+            assert_eq!(ends.len(), n);
+            assert_eq!(*ends.last().unwrap(), text.len());
+            assert!(text.starts_with("Lorem ipsum dolor sit amet"));
+            assert!(text.ends_with(['.', '!', '?']));
+        }
+        assert_eq!(lipsum_to_sentences(0).0, "");
+
+        // The first guess should usually be enough. Use a fixed seed so that
+        // this doesn't depend on the thread RNG.
+        let text = lipsum_from_seed(1000 * WORDS_PER_SENTENCE, 7);
+        assert!(text.matches(['.', '!', '?']).count() > 1000);
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(3, "n", false), ("|", "sep", false)];
+        let mut udf = LipsumParagraphs::init(cfg.as_init(), args.as_init()).unwrap();
+        let res = udf
+            .process(cfg.as_process(), args.as_process(), None)
+            .unwrap();
+        let paragraphs: Vec<_> = res.split('|').collect();
+        assert_eq!(paragraphs.len(), 3);
+        assert!(paragraphs
+            .iter()
+            .all(|p| !p.starts_with(' ') && p.ends_with(['.', '!', '?'])));
+    }
+
+    /// Run `rows` rows through `lipsum(min, max, seed)`
+    fn run_range(min: i64, max: i64, seed: i64, rows: usize) -> Vec<String> {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![
+            (min, "min", false),
+            (max, "max", false),
+            (seed, "seed", false)
+        ];
+        let mut udf = Lipsum::init(cfg.as_init(), args.as_init()).unwrap();
+        (0..rows)
+            .map(|_| {
+                udf.process(cfg.as_process(), args.as_process(), None)
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_range() {
+        let rows = run_range(3, 12, 42, 20);
+        for row in &rows {
+            let words = row.split(' ').count();
+            assert!((3..=12).contains(&words), "{row}");
+        }
+
+        // Reproducible with the same seed, but rows vary
+        assert_eq!(rows, run_range(3, 12, 42, 20));
+        assert_ne!(rows, run_range(3, 12, 43, 20));
+        assert!(rows.iter().any(|row| *row != rows[0]));
+
+        assert!(run_range(5, 5, 1, 3)
+            .iter()
+            .all(|row| row.split(' ').count() == 5));
+    }
+
+    #[test]
+    fn test_seed_unchanged() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(23, "n", false), (16, "seed", false)];
+        let mut udf = Lipsum::init(cfg.as_init(), args.as_init()).unwrap();
+        for _ in 0..2 {
+            let res = udf.process(cfg.as_process(), args.as_process(), None);
+            assert_eq!(res, Ok(lipsum_from_seed(23, 16).as_str()));
+        }
+    }
+
+    #[test]
+    fn test_init_errors() {
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(5, "min", false), (4, "max", false), (1, "seed", false)];
+        assert!(Lipsum::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(1, "min", false), (4, "max", false), (-1, "seed", false)];
+        assert!(Lipsum::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![
+            (1, "min", false),
+            (MAX_WORDS + 1, "max", false),
+            (1, "seed", false)
+        ];
+        assert!(Lipsum::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut cfg = MockUdfCfg::new();
+        let mut args = mock_args![(-1, "n", false)];
+        assert!(LipsumChars::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(MAX_CHARS + 1, "n", false)];
+        assert!(LipsumChars::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(MAX_SENTENCES + 1, "n", false)];
+        assert!(LipsumSentences::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(1, "n", false), (-5, "seed", false)];
+        assert!(LipsumChars::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(1, "n", false), (2, "sep", false)];
+        assert!(LipsumParagraphs::init(cfg.as_init(), args.as_init()).is_err());
+
+        let mut args = mock_args![(Int None, "n", false)];
+        assert!(LipsumParagraphs::init(cfg.as_init(), args.as_init()).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Adds `xmlify` to build an XML element from its arguments.
+//!
+//! Add with
+//!
+//! ```sql
+//! CREATE FUNCTION xmlify RETURNS string SONAME 'libudf_xmlify.so';
+//! ```
+//!
+//! The first argument is the name of the root element and must be a constant.
+//! Every other argument becomes a child element named after its attribute, or
+//! an attribute of the root element if its alias starts with `attr:`:
+//!
+//! ```sql
+//! SELECT xmlify('order', id AS "attr:id", sku, qty) FROM orders;
+//! -- <order id="1"><sku>A-100</sku><qty>2</qty></order>
+//! ```
+//!
+//! Names are checked during `init`, so an expression that isn't aliased (e.g.
+//! `qty + 1`) is an error rather than invalid output. Text is always escaped.
+//! Strings that are not valid UTF-8 or contain characters that XML 1.0 cannot
+//! represent are base64 encoded in child elements, which get an
+//! `encoding="base64"` attribute to mark this. Attributes have no way to mark
+//! encoded values, so such values in an `attr:` argument are an error. `NULL`
+//! arguments are left out.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use udf::prelude::*;
+use udf::MaxLenOptions;
+
+/// Prefix of aliases that create attributes rather than child elements
+const ATTR_PREFIX: &str = "attr:";
+
+/// Where an argument goes in the output
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Field {
+    Child(String),
+    Attr(String),
+}
+
+/// Check whether `name` is a valid XML element or attribute name
+///
+/// This is slightly more lenient than the spec, which only allows some
+/// Unicode ranges.
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let Some(first) = chars.next() else {
+        return false;
+    };
+    (first.is_alphabetic() || matches!(first, '_' | ':'))
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | ':' | '-' | '.' | '\u{b7}'))
+}
+
+/// Check whether XML 1.0 can represent `ch`, even as a character reference
+fn is_xml_char(ch: char) -> bool {
+    !matches!(ch, '\0'..='\u{8}' | '\u{b}' | '\u{c}' | '\u{e}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}')
+}
+
+/// Append `s` to `out`, escaping it for use as text or an attribute value
+///
+/// Whitespace in attributes and carriage returns in text are escaped too, since
+/// parsers would otherwise normalize them.
+fn push_escaped(out: &mut String, s: &str, attr: bool) {
+    for ch in s.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#13;"),
+            '"' if attr => out.push_str("&quot;"),
+            '\t' if attr => out.push_str("&#9;"),
+            '\n' if attr => out.push_str("&#10;"),
+            _ => out.push(ch),
+        }
+    }
+}
+
+/// Get the text of a value, or `None` if it is `NULL`
+///
+/// The flag is set if the text is base64 encoded.
+fn value_text(value: SqlResult) -> Option<(String, bool)> {
+    let ret = match value {
+        SqlResult::String(Some(v)) => match std::str::from_utf8(v) {
+            Ok(s) if s.chars().all(is_xml_char) => (s.to_owned(), false),
+            _ => (BASE64.encode(v), true),
+        },
+        SqlResult::Real(Some(v)) => (v.to_string(), false),
+        SqlResult::Int(Some(v)) => (v.to_string(), false),
+        SqlResult::Decimal(Some(v)) => (v.to_owned(), false),
+        _ => return None,
+    };
+    Some(ret)
+}
+
+/// Create an XML element from all arguments
+#[derive(Debug, Default)]
+struct Xmlify {
+    root: String,
+    /// Output location of each argument after the root name
+    fields: Vec<Field>,
+    res: String,
+}
+
+#[register]
+impl BasicUdf for Xmlify {
+    type Returns<'a> = &'a str;
+
+    /// Expect `xmlify(root_name, ...)` and check all names
+    fn init(cfg: &UdfCfg<Init>, args: &ArgList<Init>) -> Result<Self, String> {
+        let Some(first) = args.get(0) else {
+            return Err(
+                "xmlify takes at least 1 argument (usage: `xmlify(root_name, ...)`)".into(),
+            );
+        };
+        let value = first.value();
+        let Some(root) = value.as_string() else {
+            return Err("xmlify: the root name must be a constant string".into());
+        };
+        if !is_xml_name(root) {
+            return Err(format!("xmlify: '{root}' is not a valid XML name"));
+        }
+
+        let mut fields = Vec::with_capacity(args.len() - 1);
+        for arg in args.iter().skip(1) {
+            let attribute = arg.attribute();
+            let field = match attribute.strip_prefix(ATTR_PREFIX) {
+                Some(name) => Field::Attr(name.to_owned()),
+                None => Field::Child(attribute.to_owned()),
+            };
+            let (Field::Attr(name) | Field::Child(name)) = &field;
+            if !is_xml_name(name) {
+                return Err(format!(
+                    "xmlify: '{attribute}' is not a valid XML name; use `AS` to name the argument"
+                ));
+            }
+            if matches!(field, Field::Attr(_)) && fields.contains(&field) {
+                return Err(format!("xmlify: duplicate attribute '{name}'"));
+            }
+            fields.push(field);
+        }
+
+        cfg.set_max_len(MaxLenOptions::Blob as u64);
+        Ok(Self {
+            root: root.to_owned(),
+            fields,
+            res: String::new(),
+        })
+    }
+
+    fn process<'a>(
+        &'a mut self,
+        _cfg: &UdfCfg<Process>,
+        args: &ArgList<Process>,
+        _error: Option<NonZeroU8>,
+    ) -> Result<Self::Returns<'a>, ProcessError> {
+        let values: Vec<_> = args
+            .iter()
+            .skip(1)
+            .map(|arg| value_text(arg.value()))
+            .collect();
+        let res = &mut self.res;
+        res.clear();
+        res.push('<');
+        res.push_str(&self.root);
+
+        for (field, value) in self.fields.iter().zip(&values) {
+            if let (Field::Attr(name), Some((text, base64))) = (field, value) {
+                if *base64 {
+                    udf_log!(Warning: "xmlify: attribute '{name}' contains binary data or control characters");
+                    return Err(ProcessError);
+                }
+                res.push(' ');
+                res.push_str(name);
+                res.push_str("=\"");
+                push_escaped(res, text, true);
+                res.push('"');
+            }
+        }
+
+        let mut empty = true;
+        for (field, value) in self.fields.iter().zip(&values) {
+            let (Field::Child(name), Some((text, base64))) = (field, value) else {
+                continue;
+            };
+            if empty {
+                res.push('>');
+                empty = false;
+            }
+            res.push('<');
+            res.push_str(name);
+            if *base64 {
+                res.push_str(" encoding=\"base64\"");
+            }
+            res.push('>');
+            push_escaped(res, text, false);
+            res.push_str("</");
+            res.push_str(name);
+            res.push('>');
+        }
+
+        if empty {
+            res.push_str("/>");
+        } else {
+            res.push_str("</");
+            res.push_str(&self.root);
+            res.push('>');
+        }
+
+        Ok(&self.res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udf::mock::*;
+
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert!(is_xml_name("order"));
+        assert!(is_xml_name("soap:Body"));
+        assert!(is_xml_name("_a-b.c1"));
+        assert!(is_xml_name("名前"));
+        assert!(!is_xml_name(""));
+        assert!(!is_xml_name("1st"));
+        assert!(!is_xml_name("qty + 1"));
+        assert!(!is_xml_name("a<b"));
+    }
+
+    #[test]
+    fn test_xmlify() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![
+            ("order", "'order'", true),
+            (7, "attr:id", false),
+            ("a\"b\nc", "attr:note", false),
+            (String None, "attr:missing", true),
+            ("A&B <x>", "sku", false),
+            (2.5, "qty", false),
+            (Decimal "10.50", "price", false),
+            ("line\r\n", "text", false),
+            (String None, "gone", true),
+            (&b"\xff\x00"[..], "blob", false),
+            ("bell\x07", "ctl", false),
+        ];
+
+        let mut xmlify = Xmlify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = xmlify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(
+            res,
+            Ok(concat!(
+                r#"<order id="7" note="a&quot;b&#10;c">"#,
+                "<sku>A&amp;B &lt;x&gt;</sku><qty>2.5</qty><price>10.50</price>",
+                "<text>line&#13;\n</text>",
+                r#"<blob encoding="base64">/wA=</blob>"#,
+                r#"<ctl encoding="base64">YmVsbAc=</ctl>"#,
+                "</order>"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![("row", "'row'", true), (Int None, "a", true)];
+        let mut xmlify = Xmlify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = xmlify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok("<row/>"));
+
+        let mut arglist = mock_args![("row", "'row'", true), (1, "attr:a", false)];
+        let mut xmlify = Xmlify::init(cfg.as_init(), arglist.as_init()).unwrap();
+        let res = xmlify.process(cfg.as_process(), arglist.as_process(), None);
+        assert_eq!(res, Ok(r#"<row a="1"/>"#));
+    }
+
+    #[test]
+    fn test_binary_attr() {
+        let mut cfg = MockUdfCfg::new();
+        for value in [&b"\xff\x00"[..], b"bell\x07"] {
+            let mut arglist = mock_args![("row", "'row'", true), (value, "attr:a", false)];
+            let mut xmlify = Xmlify::init(cfg.as_init(), arglist.as_init()).unwrap();
+            let res = xmlify.process(cfg.as_process(), arglist.as_process(), None);
+            assert_eq!(res, Err(ProcessError));
+        }
+    }
+
+    #[test]
+    fn test_init_errors() {
+        let mut cfg = MockUdfCfg::new();
+        let mut arglist = mock_args![];
+        assert!(Xmlify::init(cfg.as_init(), arglist.as_init()).is_err());
+
+        let mut arglist = mock_args![(String None, "root", false)];
+        assert!(Xmlify::init(cfg.as_init(), arglist.as_init()).is_err());
+
+        let mut arglist = mock_args![("bad name", "'bad name'", true)];
+        assert!(Xmlify::init(cfg.as_init(), arglist.as_init()).is_err());
+
+        let mut arglist = mock_args![("row", "'row'", true), (1, "qty + 1", false)];
+        assert!(Xmlify::init(cfg.as_init(), arglist.as_init()).is_err());
+
+        let mut arglist = mock_args![
+            ("row", "'row'", true),
+            (1, "attr:a", false),
+            (2, "attr:a", false)
+        ];
+        assert!(Xmlify::init(cfg.as_init(), arglist.as_init()).is_err());
+    }
+}